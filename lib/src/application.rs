@@ -1,73 +1,635 @@
-use std::borrow::Cow;
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::convert::Infallible;
 use std::error::Error;
-use std::fmt::Display;
-use std::net::SocketAddr;
-use std::str::FromStr;
-use std::sync::Arc;
 
 use async_trait::async_trait;
-use futures::{StreamExt, TryStreamExt};
-use futures::future::BoxFuture;
-use hyper::{Body, Method, Request, Response, Server};
-use hyper::server::conn::AddrIncoming;
-use mongodb::{bson, Client};
-use mongodb::bson::{Bson, doc};
-use mongodb::bson::oid::ObjectId;
-use routerify::{Router, RouterBuilder, RouterService};
-use routerify::prelude::RequestExt;
+use regex::Regex;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::Value;
-use url::Url;
 
-use crate::frontend_http::{Context, DataResource, Route};
+use crate::ids::ResourceId;
 
 pub fn to_map<T: Serialize + DeserializeOwned>(data: &T) -> Option<HashMap<String, Value>>{
-    if let Ok(s) = serde_json::ser::to_string(data) {
-        if let Ok(m) = serde_json::de::from_str(&*s) {
-            m
-        } else {
-            None
+    let s = serde_json::ser::to_string(data).ok()?;
+    serde_json::de::from_str(&s).unwrap_or_default()
+}
+
+// apply an RFC 7386 JSON merge patch: objects merge recursively, null removes the key
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    if let Value::Object(patch) = patch {
+        if !target.is_object() {
+            *target = Value::Object(serde_json::Map::new());
+        }
+        let map = target.as_object_mut().unwrap();
+        for (key, value) in patch {
+            if value.is_null() {
+                map.remove(&key);
+            } else {
+                merge_patch(map.entry(key).or_insert(Value::Null), value);
+            }
         }
     } else {
-        None
+        *target = patch;
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldType {
+    Int,
+    Float,
+    Bool,
+    String,
+    // Vec<T>, sets and slices, with the element type
+    Array(Box<FieldType>),
+    // any other type, with its own descriptors if it implements Fields
+    Object(Option<Vec<Field>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Field {
     pub name: String,
-    pub is_num: bool,
-    pub is_bool: bool,
+    pub field_type: FieldType,
+    // the field is an Option<T>
+    pub nullable: bool,
+    // the field may be missing from stored documents
+    pub optional: bool,
+    // may be used in query param filters
+    pub filterable: bool,
+    // may be used in `sort`
+    pub sortable: bool,
+    // server-managed, ignored in request bodies
+    pub readonly: bool,
+    // server-only, neither accepted from requests nor included in responses
+    pub hidden: bool,
+    // accepted from requests but never included in responses
+    pub write_only: bool,
+    // migrations create an index on it
+    pub indexed: bool,
+    // the index rejects duplicates, implies `indexed`
+    pub unique: bool,
 }
 
 impl Field {
     pub fn new(name: &str) -> Self {
-        return Field {
+        Field {
             name: name.to_string(),
-            is_num: false,
-            is_bool: false
+            field_type: FieldType::String,
+            nullable: false,
+            optional: false,
+            filterable: true,
+            sortable: true,
+            readonly: false,
+            hidden: false,
+            write_only: false,
+            indexed: false,
+            unique: false,
         }
     }
+
+    pub fn is_num(&self) -> bool {
+        self.field_type == FieldType::Int || self.field_type == FieldType::Float
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.field_type == FieldType::Bool
+    }
+
+    // the field is left out of responses
+    pub fn is_private(&self) -> bool {
+        self.hidden || self.write_only
+    }
+
+    // the field can't be set through a request body
+    pub fn is_server_managed(&self) -> bool {
+        self.readonly || self.hidden
+    }
+
+    // placeholder for a server-managed field missing from a new resource
+    pub fn zero_value(&self) -> Value {
+        if self.nullable {
+            return Value::Null;
+        }
+        match self.field_type {
+            FieldType::Int => json!(0),
+            FieldType::Float => json!(0.0),
+            FieldType::Bool => json!(false),
+            FieldType::String => json!(""),
+            FieldType::Array(_) => json!([]),
+            FieldType::Object(_) => json!({}),
+        }
+    }
+
+    // descriptors of a nested struct, looking through arrays
+    pub fn nested(&self) -> Option<&Vec<Field>> {
+        let mut field_type = &self.field_type;
+        while let FieldType::Array(inner) = field_type {
+            field_type = inner;
+        }
+        match field_type {
+            FieldType::Object(fields) => fields.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+// drop fields that must not leave the server, nested structs included
+pub fn strip_private(fields: &[Field], map: &mut HashMap<String, Value>) {
+    for field in fields {
+        if field.is_private() {
+            map.remove(&field.name);
+        } else if let (Some(nested), Some(value)) = (field.nested(), map.get_mut(&field.name)) {
+            strip_private_nested(nested, value);
+        }
+    }
+}
+
+// the same for a nested struct's value, or each of them in an array
+fn strip_private_nested(fields: &[Field], value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for field in fields {
+                if field.is_private() {
+                    map.remove(&field.name);
+                } else if let (Some(nested), Some(value)) = (field.nested(), map.get_mut(&field.name)) {
+                    strip_private_nested(nested, value);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                strip_private_nested(fields, item);
+            }
+        }
+        _ => {}
+    }
+}
+
+// reset server-managed fields in a request body, to the stored values if there are any;
+// nested structs are reset the same way, array elements against the stored ones at their index
+pub fn strip_server_managed(fields: &[Field], body: &mut Value, existing: Option<&Value>) {
+    let body = match body.as_object_mut() {
+        Some(body) => body,
+        None => return,
+    };
+    for field in fields {
+        let stored = existing.and_then(|existing| existing.get(&field.name));
+        if field.is_server_managed() {
+            match stored {
+                Some(stored) => { body.insert(field.name.clone(), stored.clone()); }
+                None if field.optional => { body.remove(&field.name); }
+                None => { body.insert(field.name.clone(), field.zero_value()); }
+            }
+        } else if let (Some(nested), Some(value)) = (field.nested(), body.get_mut(&field.name)) {
+            strip_server_managed_nested(nested, value, stored);
+        }
+    }
+}
+
+fn strip_server_managed_nested(fields: &[Field], value: &mut Value, existing: Option<&Value>) {
+    match value {
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                strip_server_managed_nested(fields, item, existing.and_then(|existing| existing.get(i)));
+            }
+        }
+        value => strip_server_managed(fields, value, existing),
+    }
+}
+
+// look up a field by a dotted path such as `director.name`
+pub fn find_field<'a>(fields: &'a [Field], path: &str) -> Option<&'a Field> {
+    let (name, rest) = match path.find('.') {
+        Some(dot) => (&path[..dot], Some(&path[dot + 1..])),
+        None => (path, None),
+    };
+    let field = fields.iter().find(|field| field.name == name)?;
+    match rest {
+        Some(rest) => find_field(field.nested()?, rest),
+        None => Some(field),
+    }
+}
+
+// used by the Fields derive to find out whether a field's type has descriptors of its own
+#[doc(hidden)]
+pub struct NestedProbe<T>(pub std::marker::PhantomData<T>);
+
+#[doc(hidden)]
+pub trait NestedFields {
+    fn nested_fields(&self) -> Option<Vec<Field>>;
+}
+
+thread_local! {
+    // the nested types being described, a type met again inside itself is left opaque so
+    // recursive types like `replies: Vec<Comment>` come to an end
+    static DESCRIBING: RefCell<Vec<TypeId>> = const { RefCell::new(vec![]) };
+}
+
+// takes T off DESCRIBING again, also when its fields() panics
+struct Describing;
+
+impl Drop for Describing {
+    fn drop(&mut self) {
+        DESCRIBING.with(|describing| describing.borrow_mut().pop());
+    }
+}
+
+impl<T: Fields + 'static> NestedFields for NestedProbe<T> {
+    fn nested_fields(&self) -> Option<Vec<Field>> {
+        let id = TypeId::of::<T>();
+        if DESCRIBING.with(|describing| describing.borrow().contains(&id)) {
+            return None;
+        }
+        DESCRIBING.with(|describing| describing.borrow_mut().push(id));
+        let _describing = Describing;
+        Some(T::fields())
+    }
+}
+
+#[doc(hidden)]
+pub trait OpaqueFields {
+    fn nested_fields(&self) -> Option<Vec<Field>>;
+}
+
+// autoref fallback, only picked when T doesn't implement Fields
+impl<T> OpaqueFields for &NestedProbe<T> {
+    fn nested_fields(&self) -> Option<Vec<Field>> {
+        None
+    }
 }
 
 pub trait Fields {
     fn fields() -> Vec<Field>;
 }
 
-pub trait Filter: Default {
-    fn insert<KT: Into<String>, BT: Into<Bson>>(&mut self, key: KT, val: BT) -> Option<Bson>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    In,
+    Nin,
+    Exists,
+    Regex,
+    Contains,
+}
+
+impl Op {
+    // parse the operator out of a `field[op]` query param
+    pub fn from_param(name: &str) -> Option<Op> {
+        match name {
+            "eq" => Some(Op::Eq),
+            "ne" => Some(Op::Ne),
+            "gt" => Some(Op::Gt),
+            "gte" => Some(Op::Gte),
+            "lt" => Some(Op::Lt),
+            "lte" => Some(Op::Lte),
+            "in" => Some(Op::In),
+            "nin" => Some(Op::Nin),
+            "exists" => Some(Op::Exists),
+            "regex" => Some(Op::Regex),
+            "contains" => Some(Op::Contains),
+            _ => None,
+        }
+    }
+}
+
+// backend-neutral query tree, each Filter implementation translates it to its own syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    // for In/Nin the value is an array, for Exists a bool, for Regex/Contains a string;
+    // ids and other backend types use their serde JSON form, e.g. `{"$oid": ...}` for an ObjectId
+    Cmp { field: String, op: Op, value: Value },
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    pub fn field<N: Into<String>>(name: N) -> FieldQuery {
+        FieldQuery { name: name.into() }
+    }
+
+    pub fn and(queries: Vec<Query>) -> Query {
+        Query::And(queries)
+    }
+
+    pub fn or(queries: Vec<Query>) -> Query {
+        Query::Or(queries)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+}
+
+// builder returned by Query::field
+pub struct FieldQuery {
+    name: String,
+}
+
+impl FieldQuery {
+    pub fn cmp<V: Into<Value>>(self, op: Op, value: V) -> Query {
+        Query::Cmp { field: self.name, op, value: value.into() }
+    }
+
+    pub fn eq<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Eq, value)
+    }
+
+    pub fn ne<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Ne, value)
+    }
+
+    pub fn gt<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Gt, value)
+    }
+
+    pub fn gte<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Gte, value)
+    }
+
+    pub fn lt<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Lt, value)
+    }
+
+    pub fn lte<V: Into<Value>>(self, value: V) -> Query {
+        self.cmp(Op::Lte, value)
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn is_in<V: Into<Value>>(self, values: Vec<V>) -> Query {
+        self.cmp(Op::In, values.into_iter().map(Into::into).collect::<Vec<Value>>())
+    }
+
+    pub fn nin<V: Into<Value>>(self, values: Vec<V>) -> Query {
+        self.cmp(Op::Nin, values.into_iter().map(Into::into).collect::<Vec<Value>>())
+    }
+
+    pub fn exists(self, exists: bool) -> Query {
+        self.cmp(Op::Exists, exists)
+    }
+
+    pub fn regex<P: Into<String>>(self, pattern: P) -> Query {
+        self.cmp(Op::Regex, pattern.into())
+    }
+
+    // substring match, the needle is taken literally
+    pub fn contains<P: Into<String>>(self, needle: P) -> Query {
+        self.cmp(Op::Contains, needle.into())
+    }
+}
+
+// whether an item in its JSON form matches all the queries; this is what the queries mean for
+// every data layer: dotted paths walk into objects and arrays, numbers compare across integers
+// and floats, ranges never match across kinds of values and null also matches a missing field.
+// A Regex query whose pattern doesn't compile is an error
+pub fn matches_all(item: &Value, queries: &[Query]) -> Result<bool, regex::Error> {
+    for query in queries {
+        if !matches(item, query)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub fn matches(item: &Value, query: &Query) -> Result<bool, regex::Error> {
+    match query {
+        Query::Cmp { field, op, value } => matches_field(&lookup(item, field), *op, value),
+        Query::And(queries) => matches_all(item, queries),
+        Query::Or(queries) => {
+            for query in queries {
+                if matches(item, query)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        Query::Not(query) => Ok(!matches(item, query)?),
+    }
+}
+
+// values at a dotted path, arrays along the way are walked into and an array at the end
+// contributes both itself and its elements
+pub fn lookup<'a>(item: &'a Value, path: &str) -> Vec<&'a Value> {
+    let mut found = vec![];
+    let (head, rest) = match path.find('.') {
+        Some(dot) => (&path[..dot], Some(&path[dot + 1..])),
+        None => (path, None),
+    };
+    if let Some(value) = item.as_object().and_then(|object| object.get(head)) {
+        collect(value, rest, &mut found);
+    }
+    found
+}
+
+fn collect<'a>(value: &'a Value, rest: Option<&str>, found: &mut Vec<&'a Value>) {
+    match (value, rest) {
+        (Value::Array(items), None) => {
+            found.push(value);
+            found.extend(items.iter());
+        }
+        (_, None) => found.push(value),
+        (Value::Object(_), Some(rest)) => found.extend(lookup(value, rest)),
+        (Value::Array(items), Some(rest)) => {
+            for item in items.iter().filter(|item| item.is_object()) {
+                found.extend(lookup(item, rest));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn matches_field(found: &[&Value], op: Op, value: &Value) -> Result<bool, regex::Error> {
+    let ordered = |accept: fn(Ordering) -> bool| found.iter().any(|candidate| compare(candidate, value).map(accept).unwrap_or(false));
+    let strings = || found.iter().filter_map(|candidate| candidate.as_str());
+    Ok(match op {
+        Op::Eq => equals_any(found, value),
+        Op::Ne => !equals_any(found, value),
+        Op::Gt => ordered(|ordering| ordering == Ordering::Greater),
+        Op::Gte => ordered(|ordering| ordering != Ordering::Less),
+        Op::Lt => ordered(|ordering| ordering == Ordering::Less),
+        Op::Lte => ordered(|ordering| ordering != Ordering::Greater),
+        Op::In => in_list(found, value),
+        Op::Nin => !in_list(found, value),
+        Op::Exists => found.is_empty() != value.as_bool().unwrap_or(true),
+        Op::Regex => match value.as_str() {
+            Some(pattern) => {
+                let pattern = Regex::new(pattern)?;
+                strings().any(|candidate| pattern.is_match(candidate))
+            }
+            None => false,
+        },
+        Op::Contains => {
+            let needle = match value {
+                Value::String(needle) => needle.clone(),
+                other => other.to_string(),
+            };
+            strings().any(|candidate| candidate.contains(&needle))
+        }
+    })
+}
+
+fn equals_any(found: &[&Value], value: &Value) -> bool {
+    if value.is_null() && found.is_empty() {
+        return true;
+    }
+    found.iter().any(|candidate| equals(candidate, value))
+}
+
+fn in_list(found: &[&Value], values: &Value) -> bool {
+    match values {
+        Value::Array(values) => values.iter().any(|value| equals_any(found, value)),
+        _ => false,
+    }
+}
+
+pub fn equals(a: &Value, b: &Value) -> bool {
+    compare(a, b) == Some(Ordering::Equal) || a == b
+}
+
+// order within the same kind of value, numbers compare across their representations,
+// ObjectIds and dates by what they stand for; None across kinds
+pub fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        _ => match (Extended::of(a)?, Extended::of(b)?) {
+            (Extended::ObjectId(a), Extended::ObjectId(b)) => Some(a.cmp(b)),
+            (Extended::Date(a), Extended::Date(b)) => Some(a.cmp(&b)),
+            _ => None,
+        },
+    }
+}
+
+// the few backend types whose serde JSON form is an extended JSON object, e.g. `{"$oid": ...}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extended<'a> {
+    // the hex string, which orders the same as the id's bytes
+    ObjectId(&'a str),
+    // milliseconds since the unix epoch
+    Date(i64),
+}
+
+impl<'a> Extended<'a> {
+    pub fn of(value: &'a Value) -> Option<Extended<'a>> {
+        let object = value.as_object().filter(|object| object.len() == 1)?;
+        if let Some(oid) = object.get("$oid") {
+            return oid.as_str().map(Extended::ObjectId);
+        }
+        match object.get("$date")? {
+            Value::Number(millis) => millis.as_i64().map(Extended::Date),
+            Value::Object(long) => long.get("$numberLong")?.as_str()?.parse().ok().map(Extended::Date),
+            _ => None,
+        }
+    }
+}
+
+pub trait Filter: Default + Clone {
+    // equality match on a single key, returns the value it replaced
+    fn insert<KT: Into<String>, V: Into<Value>>(&mut self, key: KT, val: V) -> Option<Value>;
+    // and the query with the conditions already in the filter
+    fn add(&mut self, query: Query);
+}
+
+// filter keeping the Query tree as is, for data layers that evaluate or translate it themselves
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryFilter {
+    // all of them have to match
+    pub queries: Vec<Query>,
+}
+
+impl Filter for QueryFilter {
+    fn insert<KT: Into<String>, V: Into<Value>>(&mut self, key: KT, val: V) -> Option<Value> {
+        // like Document::insert, an equality match replaces the previous one on the same key
+        let key = key.into();
+        let previous = self.queries.iter()
+            .position(|query| matches!(query, Query::Cmp { field, op: Op::Eq, .. } if *field == key))
+            .and_then(|i| match self.queries.remove(i) {
+                Query::Cmp { value, .. } => Some(value),
+                _ => None,
+            });
+        self.queries.push(Query::field(key).eq(val));
+        previous
+    }
+
+    fn add(&mut self, query: Query) {
+        self.queries.push(query);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetrieveOptions {
+    pub limit: Option<u64>,
+    pub skip: Option<u64>,
+    // applied in order, earlier keys take precedence
+    pub sort: Vec<(String, SortOrder)>,
+    // only load these fields, the target type must tolerate the others being absent
+    pub projection: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsertManyOptions {
+    // stop at the first failing item instead of attempting the rest
+    pub ordered: bool,
+}
+
+impl Default for InsertManyOptions {
+    fn default() -> Self {
+        InsertManyOptions { ordered: true }
+    }
+}
+
+// outcome of update_one, update_many and replace_one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UpdateResult {
+    pub matched: u64,
+    pub modified: u64,
+}
+
+// outcome of delete_one and delete_many
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeleteResult {
+    pub deleted: u64,
 }
 
 // driver-agnostic database representation
 #[async_trait]
 pub trait Database {
     type Filter: Filter + Send + Sync;
-    type Error: Error + Send + Sync;
+    // backends decide which of their failures map to conflicts and the like
+    type Error: Error + Send + Sync + Into<crate::error::Error>;
     async fn retrieve_one<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter) -> Result<Option<T>, Self::Error>;
-    async fn retrieve_many<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter) -> Result<Vec<T>, Self::Error>;
-    async fn insert_one<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, item: T) -> Result<u32, Self::Error>;
-    async fn insert_many<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, items: Vec<T>) -> Result<Vec<u32>, Self::Error>;
+    async fn retrieve_many<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, options: RetrieveOptions) -> Result<Vec<T>, Self::Error>;
+    // returns the stored id, whether the item carried it or the backend assigned it
+    async fn insert_one<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, item: T) -> Result<Id, Self::Error>;
+    // one id or error per attempted item, in the order of `items`; an ordered batch stops after
+    // its first failure, the items before it stay stored. The outer error is for the batch as a
+    // whole, e.g. a lost connection
+    async fn insert_many<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, items: Vec<T>, options: InsertManyOptions) -> Result<Vec<Result<Id, crate::error::Error>>, Self::Error>;
+    // `changes` is serialized to a set of fields that overwrite the stored ones, other fields are kept
+    async fn update_one<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error>;
+    async fn update_many<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error>;
+    async fn replace_one<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, item: T) -> Result<UpdateResult, Self::Error>;
+    async fn delete_one(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error>;
+    async fn delete_many(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error>;
+    async fn count(&self, table_name: String, filter: Self::Filter) -> Result<u64, Self::Error>;
 }
 
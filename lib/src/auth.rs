@@ -0,0 +1,431 @@
+use std::convert::TryFrom;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request};
+use hyper::header::{AUTHORIZATION, COOKIE};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::RsaPublicKey;
+use rsa::signature::Verifier;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::application::{Database, Filter};
+use crate::error::Error;
+use crate::frontend_http::DataResource;
+use crate::middleware::{Flow, Middleware, RouteInfo};
+
+// identifies the caller of a request as a principal of type P, for Context::generate to call
+#[async_trait]
+pub trait Authenticator<P>: Send + Sync {
+    // None when the request carries no credentials of this kind, Unauthorized when they're wrong
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<P>, Error>;
+
+    // turn the principal into another type, so different authenticators can be chained
+    fn map<Q>(self, map: fn(P) -> Q) -> Mapped<Self, P, Q> where Self: Sized {
+        Mapped { inner: self, map }
+    }
+}
+
+pub struct Mapped<A, P, Q> {
+    inner: A,
+    map: fn(P) -> Q,
+}
+
+#[async_trait]
+impl<A, P, Q> Authenticator<Q> for Mapped<A, P, Q> where A: Authenticator<P>, P: Send, Q: Send {
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<Q>, Error> {
+        Ok(self.inner.authenticate(req).await?.map(self.map))
+    }
+}
+
+// tries each authenticator in turn, the first one to identify the caller wins
+pub struct Authenticators<P> {
+    chain: Vec<Box<dyn Authenticator<P>>>,
+}
+
+impl<P> Default for Authenticators<P> {
+    fn default() -> Self {
+        Authenticators { chain: vec![] }
+    }
+}
+
+impl<P: Send> Authenticators<P> {
+    pub fn new() -> Self {
+        Authenticators::default()
+    }
+
+    pub fn with<A: Authenticator<P> + 'static>(mut self, authenticator: A) -> Self {
+        self.chain.push(Box::new(authenticator));
+        self
+    }
+
+    // the caller, Unauthorized for anonymous requests
+    pub async fn authenticate(&self, req: &Request<Body>) -> Result<P, Error> {
+        self.identify(req).await?.ok_or(Error::Unauthorized)
+    }
+
+    // the caller if there are credentials, wrong ones are rejected with the first authenticator's error
+    // unless a later one accepts the request
+    pub async fn identify(&self, req: &Request<Body>) -> Result<Option<P>, Error> {
+        let mut rejected = None;
+        for authenticator in &self.chain {
+            match authenticator.authenticate(req).await {
+                Ok(Some(principal)) => return Ok(Some(principal)),
+                Ok(None) => {}
+                Err(err) => {
+                    rejected.get_or_insert(err);
+                }
+            }
+        }
+        match rejected {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+// runs the authenticators ahead of the routes and leaves the principal in the request's extensions,
+// where Context::generate can take it with `req.extensions_mut().remove::<P>()`
+pub struct Authenticate<P> {
+    pub authenticators: Authenticators<P>,
+    // anonymous requests are turned away instead of passed on without a principal
+    pub required: bool,
+}
+
+#[async_trait]
+impl<P> Middleware for Authenticate<P> where P: Send + Sync + 'static {
+    async fn before(&self, mut req: Request<Body>, _route: &RouteInfo) -> Result<Flow, Error> {
+        match self.authenticators.identify(&req).await? {
+            Some(principal) => {
+                req.extensions_mut().insert(principal);
+            }
+            None if self.required => return Err(Error::Unauthorized),
+            None => {}
+        }
+        Ok(Flow::Continue(req))
+    }
+}
+
+// the credentials of an `Authorization: <scheme> <credentials>` header
+fn authorization<'a>(req: &'a Request<Body>, scheme: &str) -> Option<&'a str> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (given, credentials) = value.split_once(' ')?;
+    if given.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+
+// the value of the cookie called `name`
+pub fn cookie<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// HTTP Basic, `verify` gets the user name and password and returns the principal they belong to
+pub struct Basic<P> {
+    pub verify: fn(String, String) -> BoxFuture<'static, Option<P>>,
+}
+
+#[async_trait]
+impl<P: Send> Authenticator<P> for Basic<P> {
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<P>, Error> {
+        let credentials = match authorization(req, "Basic") {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let decoded = STANDARD.decode(credentials).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(Error::Unauthorized)?;
+        let (user, password) = decoded.split_once(':').ok_or(Error::Unauthorized)?;
+        (self.verify)(user.to_string(), password.to_string()).await.ok_or(Error::Unauthorized).map(Some)
+    }
+}
+
+// keys are stored hashed, so a leaked collection doesn't leak them
+pub fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `Authorization: Bearer <key>` API keys, the key resource K whose `hash_field` holds hash_api_key
+// of the key is the principal
+pub struct ApiKeys<K, DB> {
+    pub data_layer: Arc<DB>,
+    pub hash_field: String,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K, DB> ApiKeys<K, DB> {
+    pub fn new(data_layer: Arc<DB>, hash_field: &str) -> Self {
+        ApiKeys { data_layer, hash_field: hash_field.to_string(), key: PhantomData }
+    }
+}
+
+#[async_trait]
+impl<K, DB> Authenticator<K> for ApiKeys<K, DB> where K: DataResource + Send, DB: Database + Send + Sync {
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<K>, Error> {
+        let key = match authorization(req, "Bearer") {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let mut filter = DB::Filter::default();
+        filter.insert(self.hash_field.as_str(), hash_api_key(key));
+        let found: Option<K> = self.data_layer.retrieve_one(K::get_collection_name(), filter).await.map_err(Into::into)?;
+        found.ok_or(Error::Unauthorized).map(Some)
+    }
+}
+
+// a key JWTs may be signed with, the token's `alg` picks it
+pub enum JwtKey {
+    Hs256(Vec<u8>),
+    Rs256(RsaPublicKey),
+}
+
+impl JwtKey {
+    pub fn hs256<S: Into<Vec<u8>>>(secret: S) -> Self {
+        JwtKey::Hs256(secret.into())
+    }
+
+    // from a `PUBLIC KEY` or `RSA PUBLIC KEY` PEM
+    pub fn rs256_pem(pem: &str) -> io::Result<Self> {
+        RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+            .map(JwtKey::Rs256)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid RSA public key: {}", err)))
+    }
+
+    fn alg(&self) -> &'static str {
+        match self {
+            JwtKey::Hs256(_) => "HS256",
+            JwtKey::Rs256(_) => "RS256",
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            JwtKey::Hs256(secret) => match hmac_sha256(secret) {
+                Some(mut mac) => {
+                    mac.update(message);
+                    mac.verify_slice(signature).is_ok()
+                }
+                None => false,
+            },
+            JwtKey::Rs256(public) => match Signature::try_from(signature) {
+                Ok(signature) => VerifyingKey::<Sha256>::new(public.clone()).verify(message, &signature).is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+fn hmac_sha256(secret: &[u8]) -> Option<Hmac<Sha256>> {
+    Hmac::<Sha256>::new_from_slice(secret).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+// signs `claims` as an HS256 token, e.g. to hand out after a login
+pub fn encode_hs256<C: Serialize>(claims: &C, secret: &[u8]) -> Result<String, Error> {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).map_err(Error::backend)?);
+    let message = format!("{}.{}", header, payload);
+    let mut mac = hmac_sha256(secret).ok_or_else(|| Error::bad_request("invalid secret"))?;
+    mac.update(message.as_bytes());
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())))
+}
+
+// `Authorization: Bearer` JWTs verified against local keys, the claims C are the principal;
+// `exp` and `nbf` are checked when present, `iss` and `aud` when configured
+pub struct Jwt<C> {
+    keys: Vec<JwtKey>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // seconds of clock skew tolerated on `exp` and `nbf`
+    pub leeway: u64,
+    claims: PhantomData<fn() -> C>,
+}
+
+impl<C: DeserializeOwned> Jwt<C> {
+    pub fn new(key: JwtKey) -> Self {
+        Jwt { keys: vec![key], issuer: None, audience: None, leeway: 60, claims: PhantomData }
+    }
+
+    // accept tokens signed with another key as well, e.g. while rotating them
+    pub fn key(mut self, key: JwtKey) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    pub fn issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    // whether the token is a JWT for these keys: its header is a JSON object with the `alg` of one
+    // of them and a `typ` of JWT, if any; whether it is valid is up to decode
+    fn accepts(&self, token: &str) -> bool {
+        let header = match token.split('.').collect::<Vec<&str>>()[..] {
+            [header, _, _] => header,
+            _ => return false,
+        };
+        let header: Value = match URL_SAFE_NO_PAD.decode(header).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()) {
+            Some(header) => header,
+            None => return false,
+        };
+        let typ = match header.get("typ") {
+            None => true,
+            Some(typ) => typ.as_str().is_some_and(|typ| typ.eq_ignore_ascii_case("JWT")),
+        };
+        let alg = header.get("alg").and_then(Value::as_str);
+        typ && self.keys.iter().any(|key| Some(key.alg()) == alg)
+    }
+
+    // the claims of a valid token, Unauthorized for anything else
+    pub fn decode(&self, token: &str) -> Result<C, Error> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let (header, payload, signature) = match parts[..] {
+            [header, payload, signature] => (header, payload, signature),
+            _ => return Err(Error::Unauthorized),
+        };
+        let decode_json = |part: &str| -> Result<Value, Error> {
+            let bytes = URL_SAFE_NO_PAD.decode(part).map_err(|_| Error::Unauthorized)?;
+            serde_json::from_slice(&bytes).map_err(|_| Error::Unauthorized)
+        };
+
+        // the algorithm comes from the token but has to match a configured key, so `none` or
+        // an HS256 token "signed" with a public key never verifies
+        let alg = decode_json(header)?.get("alg").and_then(Value::as_str).map(String::from).ok_or(Error::Unauthorized)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| Error::Unauthorized)?;
+        let message = format!("{}.{}", header, payload);
+        let verified = self.keys.iter()
+            .filter(|key| key.alg() == alg)
+            .any(|key| key.verify(message.as_bytes(), &signature));
+        if !verified {
+            return Err(Error::Unauthorized);
+        }
+
+        let claims = decode_json(payload)?;
+        let now = now();
+        if let Some(exp) = claims.get("exp").and_then(Value::as_u64) {
+            if now > exp.saturating_add(self.leeway) {
+                return Err(Error::Unauthorized);
+            }
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(Value::as_u64) {
+            if now.saturating_add(self.leeway) < nbf {
+                return Err(Error::Unauthorized);
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(Error::Unauthorized);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err(Error::Unauthorized);
+            }
+        }
+        serde_json::from_value(claims).map_err(|_| Error::Unauthorized)
+    }
+}
+
+#[async_trait]
+impl<C: DeserializeOwned + Send> Authenticator<C> for Jwt<C> {
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<C>, Error> {
+        match authorization(req, "Bearer") {
+            // anything else may be an API key, even with dots in it
+            Some(token) if self.accepts(token) => self.decode(token).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+// stateless sessions, the claims C are kept in a cookie signed as an HS256 token
+pub struct SessionCookie<C> {
+    pub name: String,
+    secret: Vec<u8>,
+    jwt: Jwt<C>,
+    // only sent over HTTPS
+    pub secure: bool,
+    // seconds the session lasts, also set as `exp` unless the claims have one
+    pub max_age: Option<u64>,
+}
+
+impl<C: DeserializeOwned + Serialize> SessionCookie<C> {
+    pub fn new<N: Into<String>, S: Into<Vec<u8>>>(name: N, secret: S) -> Self {
+        let secret = secret.into();
+        SessionCookie {
+            name: name.into(),
+            jwt: Jwt::new(JwtKey::hs256(secret.clone())),
+            secret,
+            secure: true,
+            max_age: None,
+        }
+    }
+
+    // `Set-Cookie` header value starting a session for `claims`
+    pub fn issue(&self, claims: &C) -> Result<String, Error> {
+        let mut claims = serde_json::to_value(claims).map_err(Error::backend)?;
+        let mut attributes = String::new();
+        if let Some(max_age) = self.max_age {
+            if let Some(claims) = claims.as_object_mut() {
+                claims.entry("exp").or_insert_with(|| json!(now() + max_age));
+            }
+            attributes.push_str(&format!("; Max-Age={}", max_age));
+        }
+        let token = encode_hs256(&claims, &self.secret)?;
+        Ok(format!("{}={}{}", self.name, token, self.attributes(&attributes)))
+    }
+
+    // `Set-Cookie` header value ending the session
+    pub fn clear(&self) -> String {
+        format!("{}={}", self.name, self.attributes("; Max-Age=0"))
+    }
+
+    fn attributes(&self, extra: &str) -> String {
+        let secure = if self.secure { "; Secure" } else { "" };
+        format!("{}; Path=/; HttpOnly; SameSite=Lax{}", extra, secure)
+    }
+}
+
+#[async_trait]
+impl<C: DeserializeOwned + Serialize + Send> Authenticator<C> for SessionCookie<C> {
+    async fn authenticate(&self, req: &Request<Body>) -> Result<Option<C>, Error> {
+        match cookie(req, &self.name) {
+            Some(token) if !token.is_empty() => self.jwt.decode(token).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::application::{compare, Database, DeleteResult, equals, Extended, InsertManyOptions, lookup, matches_all, QueryFilter, RetrieveOptions, SortOrder, UpdateResult};
+use crate::ids::ResourceId;
+use crate::migrate::{Migrate, MigrationStep, Schema};
+
+#[derive(Debug)]
+pub enum MemoryError {
+    Serialize(serde_json::Error),
+    Deserialize(serde_json::Error),
+    // items are stored as JSON objects
+    NotAnObject,
+    // an insert reused an `_id` that is already stored
+    DuplicateKey(Value),
+    // a Regex query with a pattern that doesn't compile
+    InvalidPattern(regex::Error),
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryError::Serialize(err) => write!(f, "error serializing document: {}", err),
+            MemoryError::Deserialize(err) => write!(f, "error deserializing document: {}", err),
+            MemoryError::NotAnObject => write!(f, "error serializing document: not an object"),
+            MemoryError::DuplicateKey(id) => write!(f, "duplicate _id {}", id),
+            MemoryError::InvalidPattern(err) => write!(f, "invalid regex: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+impl From<regex::Error> for MemoryError {
+    fn from(err: regex::Error) -> Self {
+        MemoryError::InvalidPattern(err)
+    }
+}
+
+impl From<MemoryError> for crate::error::Error {
+    fn from(err: MemoryError) -> Self {
+        match err {
+            MemoryError::DuplicateKey(_) => crate::error::Error::Conflict("a resource with this key already exists".to_string()),
+            err => crate::error::Error::backend(err),
+        }
+    }
+}
+
+// stored items are JSON objects
+fn to_document<T: Serialize>(item: &T) -> Result<Value, MemoryError> {
+    match serde_json::to_value(item).map_err(MemoryError::Serialize)? {
+        document @ Value::Object(_) => Ok(document),
+        _ => Err(MemoryError::NotAnObject),
+    }
+}
+
+fn from_document<T: DeserializeOwned>(document: Value) -> Result<T, MemoryError> {
+    serde_json::from_value(document).map_err(MemoryError::Deserialize)
+}
+
+// data layer keeping every collection in memory as JSON objects, meant for tests and prototyping;
+// queries match as application::matches_all decides, sorts order kinds of values like mongo
+#[derive(Default)]
+pub struct DbMemory {
+    // documents of each collection in insertion order
+    collections: RwLock<HashMap<String, Vec<Value>>>,
+    // the last integer id assigned in each collection, deleting an item doesn't hand its id out again
+    sequences: Mutex<HashMap<String, i64>>,
+}
+
+impl DbMemory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, Vec<Value>>> {
+        // a panic while holding the lock can't leave a collection half-written, so poisoning is ignored
+        self.collections.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, Vec<Value>>> {
+        self.collections.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // the stored documents matching the filter, in insertion order
+    fn select(&self, table_name: &str, filter: &QueryFilter) -> Result<Vec<Value>, MemoryError> {
+        let collections = self.read();
+        let mut selected = vec![];
+        for document in collections.get(table_name).into_iter().flatten() {
+            if matches_all(document, &filter.queries)? {
+                selected.push(document.clone());
+            }
+        }
+        Ok(selected)
+    }
+
+    // call `apply` on up to `limit` matching documents, returns how many matched and how many it changed
+    fn modify<F: FnMut(&mut Map<String, Value>) -> bool>(&self, table_name: &str, filter: &QueryFilter, limit: usize, mut apply: F) -> Result<UpdateResult, MemoryError> {
+        let mut collections = self.write();
+        let mut result = UpdateResult::default();
+        for document in collections.entry(table_name.to_string()).or_default() {
+            if result.matched as usize == limit {
+                break;
+            }
+            if matches_all(document, &filter.queries)? {
+                result.matched += 1;
+                if document.as_object_mut().map(&mut apply).unwrap_or(false) {
+                    result.modified += 1;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn remove(&self, table_name: &str, filter: &QueryFilter, limit: usize) -> Result<DeleteResult, MemoryError> {
+        let mut collections = self.write();
+        let documents = collections.entry(table_name.to_string()).or_default();
+        let mut deleted = 0;
+        let mut i = 0;
+        while i < documents.len() && deleted < limit {
+            if matches_all(&documents[i], &filter.queries)? {
+                documents.remove(i);
+                deleted += 1;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(DeleteResult { deleted: deleted as u64 })
+    }
+}
+
+// the stored `_id`, assigning one when the document has none: the next number for integer ids,
+// an ObjectId in its `{"$oid": ...}` form otherwise
+fn prepare_insert<Id: ResourceId>(documents: &[Value], mut document: Value, sequence: &mut i64) -> Result<Value, MemoryError> {
+    let id = document.get("_id").cloned();
+    match id {
+        None if Id::INTEGER => {
+            let stored = documents.iter().filter_map(|stored| stored["_id"].as_i64()).max().unwrap_or(0);
+            *sequence = std::cmp::max(*sequence, stored) + 1;
+            document["_id"] = Value::from(*sequence);
+        }
+        None => {
+            document["_id"] = json!({ "$oid": ObjectId::new().to_hex() });
+        }
+        Some(id) if documents.iter().any(|stored| equals(&stored["_id"], &id)) => {
+            return Err(MemoryError::DuplicateKey(id));
+        }
+        Some(_) => {}
+    }
+    Ok(document)
+}
+
+fn set_fields(document: &mut Map<String, Value>, changes: &Value) -> bool {
+    let mut modified = false;
+    for (key, value) in changes.as_object().into_iter().flatten() {
+        if document.get(key) != Some(value) {
+            document.insert(key.clone(), value.clone());
+            modified = true;
+        }
+    }
+    modified
+}
+
+#[async_trait]
+impl Database for DbMemory {
+    type Filter = QueryFilter;
+    type Error = MemoryError;
+
+    async fn retrieve_one<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter) -> Result<Option<T>, Self::Error> {
+        match self.select(&table_name, &filter)?.into_iter().next() {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn retrieve_many<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, options: RetrieveOptions) -> Result<Vec<T>, Self::Error> {
+        let mut documents = self.select(&table_name, &filter)?;
+        if !options.sort.is_empty() {
+            documents.sort_by(|a, b| {
+                for (key, order) in &options.sort {
+                    let ordering = compare_sort_keys(lookup(a, key).first().copied(), lookup(b, key).first().copied());
+                    let ordering = if *order == SortOrder::Ascending { ordering } else { ordering.reverse() };
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                }
+                Ordering::Equal
+            });
+        }
+
+        let skip = options.skip.unwrap_or(0) as usize;
+        let limit = options.limit.map(|limit| limit as usize).unwrap_or(usize::MAX);
+        let mut res = vec![];
+        for document in documents.into_iter().skip(skip).take(limit) {
+            let document = match &options.projection {
+                Some(keys) => project(&document, keys),
+                None => document,
+            };
+            res.push(from_document(document)?);
+        }
+        Ok(res)
+    }
+
+    async fn insert_one<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, item: T) -> Result<Id, Self::Error> {
+        let document = to_document(&item)?;
+        let mut collections = self.write();
+        let mut sequences = self.sequences.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let sequence = sequences.entry(table_name.clone()).or_default();
+        let documents = collections.entry(table_name).or_default();
+        let document = prepare_insert::<Id>(documents, document, sequence)?;
+        let id = document["_id"].clone();
+        documents.push(document);
+        from_document(id)
+    }
+
+    async fn insert_many<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, items: Vec<T>, options: InsertManyOptions) -> Result<Vec<Result<Id, crate::error::Error>>, Self::Error> {
+        let mut collections = self.write();
+        let mut sequences = self.sequences.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let sequence = sequences.entry(table_name.clone()).or_default();
+        let documents = collections.entry(table_name).or_default();
+        let mut results = vec![];
+        for item in items {
+            let inserted = to_document(&item)
+                .and_then(|document| prepare_insert::<Id>(documents, document, sequence))
+                .and_then(|document| {
+                    let id = from_document(document["_id"].clone())?;
+                    documents.push(document);
+                    Ok(id)
+                });
+            // like mongo, an unordered batch goes on with the remaining items
+            let failed = inserted.is_err();
+            results.push(inserted.map_err(Into::into));
+            if failed && options.ordered {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    async fn update_one<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error> {
+        let changes = to_document(&changes)?;
+        self.modify(&table_name, &filter, 1, |document| set_fields(document, &changes))
+    }
+
+    async fn update_many<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error> {
+        let changes = to_document(&changes)?;
+        self.modify(&table_name, &filter, usize::MAX, |document| set_fields(document, &changes))
+    }
+
+    async fn replace_one<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, item: T) -> Result<UpdateResult, Self::Error> {
+        let replacement = to_document(&item)?;
+        self.modify(&table_name, &filter, 1, |document| {
+            // the stored `_id` always survives a replace
+            let mut replaced = Map::new();
+            if let Some(id) = document.get("_id") {
+                replaced.insert("_id".to_string(), id.clone());
+            }
+            replaced.extend(replacement.as_object().into_iter().flatten()
+                .filter(|(key, _)| *key != "_id")
+                .map(|(key, value)| (key.clone(), value.clone())));
+            let modified = replaced != *document;
+            *document = replaced;
+            modified
+        })
+    }
+
+    async fn delete_one(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error> {
+        self.remove(&table_name, &filter, 1)
+    }
+
+    async fn delete_many(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error> {
+        self.remove(&table_name, &filter, usize::MAX)
+    }
+
+    async fn count(&self, table_name: String, filter: Self::Filter) -> Result<u64, Self::Error> {
+        Ok(self.select(&table_name, &filter)?.len() as u64)
+    }
+}
+
+// collections have no shape or indexes to keep track of, migrations only create them
+#[async_trait]
+impl Migrate for DbMemory {
+    async fn plan(&self, schemas: &[Schema]) -> Result<Vec<MigrationStep>, Self::Error> {
+        let collections = self.read();
+        Ok(schemas.iter()
+            .filter(|schema| !collections.contains_key(&schema.collection))
+            .map(|schema| MigrationStep::CreateCollection(schema.clone()))
+            .collect())
+    }
+
+    async fn apply(&self, step: &MigrationStep) -> Result<(), Self::Error> {
+        if let MigrationStep::CreateCollection(schema) = step {
+            self.write().entry(schema.collection.clone()).or_default();
+        }
+        Ok(())
+    }
+}
+
+// mongo's sort order between kinds of values, a missing field sorts like null
+fn kind_rank(value: Option<&Value>) -> u8 {
+    match value {
+        None | Some(Value::Null) => 1,
+        Some(Value::Number(_)) => 2,
+        Some(Value::String(_)) => 3,
+        Some(Value::Array(_)) => 5,
+        Some(Value::Bool(_)) => 8,
+        Some(object) => match Extended::of(object) {
+            Some(Extended::ObjectId(_)) => 7,
+            Some(Extended::Date(_)) => 9,
+            None => 4,
+        },
+    }
+}
+
+fn compare_sort_keys(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    kind_rank(a).cmp(&kind_rank(b)).then_with(|| match (a, b) {
+        (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+        _ => Ordering::Equal,
+    })
+}
+
+// keep `_id` and the listed (possibly dotted) keys
+fn project(document: &Value, keys: &[String]) -> Value {
+    let mut projected = Map::new();
+    if let Some(id) = document.get("_id") {
+        projected.insert("_id".to_string(), id.clone());
+    }
+    projected.extend(pick(document, keys));
+    Value::Object(projected)
+}
+
+fn pick(document: &Value, keys: &[String]) -> Map<String, Value> {
+    let mut picked = Map::new();
+    for key in keys {
+        let (head, rest) = match key.find('.') {
+            Some(dot) => (&key[..dot], Some(key[dot + 1..].to_string())),
+            None => (key.as_str(), None),
+        };
+        let value = match (document.get(head), rest) {
+            (Some(value), None) => value.clone(),
+            (Some(nested @ Value::Object(_)), Some(rest)) => {
+                let mut inner = pick(nested, &[rest]);
+                // merge with what an earlier key already kept from the same sub-document
+                if let Some(Value::Object(kept)) = picked.get(head) {
+                    let mut kept = kept.clone();
+                    kept.extend(inner);
+                    inner = kept;
+                }
+                Value::Object(inner)
+            }
+            _ => continue,
+        };
+        picked.insert(head.to_string(), value);
+    }
+    picked
+}
@@ -1,24 +1,294 @@
-use std::collections::HashMap;
-use std::error::Error;
 use mongodb::{bson, Client};
 use bson::Document;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use crate::application::{Database, Filter};
+use crate::application::{Database, DeleteResult, Filter, InsertManyOptions, Op, Query, RetrieveOptions, SortOrder, UpdateResult};
 use async_trait::async_trait;
 use futures::TryStreamExt;
-use mongodb::bson::Bson;
-use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, doc};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{ClientOptions, Collation, CollectionOptions, CountOptions, CreateCollectionOptions, DeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReadConcern, ReadPreference, ReplaceOptions, ReturnDocument, SelectionCriteria, UpdateOptions, WriteConcern};
+use mongodb::{Collection, IndexModel};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use crate::ids::ResourceId;
+use crate::migrate::{index_name, json_schema, Migrate, MigrationStep, Schema};
 
 impl Filter for Document {
-    fn insert<KT: Into<String>, BT: Into<Bson>>(&mut self, key: KT, val: BT) -> Option<Bson> {
-        self.insert(key.into(), val.into())
+    fn insert<KT: Into<String>, V: Into<Value>>(&mut self, key: KT, val: V) -> Option<Value> {
+        self.insert(key.into(), to_bson(val.into())).map(Bson::into_relaxed_extjson)
+    }
+
+    fn add(&mut self, query: Query) {
+        let translated = to_document(query);
+        if translated.keys().any(|key| self.contains_key(key)) {
+            // don't overwrite an existing condition on the same key
+            let existing = std::mem::take(self);
+            *self = doc! { "$and": [existing, translated] };
+        } else {
+            self.extend(translated);
+        }
+    }
+}
+
+// filter values in their JSON form, objects are read as extended JSON so `{"$oid": ...}` becomes an ObjectId again
+pub(crate) fn to_bson(value: Value) -> Bson {
+    match value {
+        Value::Null => Bson::Null,
+        Value::Bool(b) => Bson::Boolean(b),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) if i32::try_from(n).is_ok() => Bson::Int32(n as i32),
+            Some(n) => Bson::Int64(n),
+            None => Bson::Double(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => Bson::String(s),
+        Value::Array(items) => Bson::Array(items.into_iter().map(to_bson).collect()),
+        // not extended JSON after all, keep it as a plain document
+        Value::Object(map) => Bson::try_from(Value::Object(map.clone()))
+            .unwrap_or_else(|_| Bson::Document(map.into_iter().map(|(key, value)| (key, to_bson(value))).collect())),
+    }
+}
+
+// translate a Query into mongo's query syntax
+fn to_document(query: Query) -> Document {
+    match query {
+        Query::Cmp { field, op, value } => {
+            let value = to_bson(value);
+            let condition = match op {
+                Op::Eq => value,
+                Op::Ne => Bson::Document(doc! { "$ne": value }),
+                Op::Gt => Bson::Document(doc! { "$gt": value }),
+                Op::Gte => Bson::Document(doc! { "$gte": value }),
+                Op::Lt => Bson::Document(doc! { "$lt": value }),
+                Op::Lte => Bson::Document(doc! { "$lte": value }),
+                Op::In => Bson::Document(doc! { "$in": value }),
+                Op::Nin => Bson::Document(doc! { "$nin": value }),
+                Op::Exists => Bson::Document(doc! { "$exists": value }),
+                Op::Regex => Bson::Document(doc! { "$regex": value }),
+                Op::Contains => {
+                    let needle = match value {
+                        Bson::String(needle) => escape_regex(&needle),
+                        other => escape_regex(&other.to_string()),
+                    };
+                    Bson::Document(doc! { "$regex": needle })
+                }
+            };
+            let mut document = Document::new();
+            document.insert(field, condition);
+            document
+        }
+        Query::And(queries) => doc! { "$and": queries.into_iter().map(to_document).collect::<Vec<Document>>() },
+        Query::Or(queries) => doc! { "$or": queries.into_iter().map(to_document).collect::<Vec<Document>>() },
+        // $not only applies to a single field's operator, $nor negates a whole expression
+        Query::Not(query) => doc! { "$nor": [to_document(*query)] },
+    }
+}
+
+fn escape_regex(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// the update document of update_one and update_many, the top-level fields of `changes` overwrite
+// the stored ones, nested documents as a whole
+pub(crate) fn set_update<U: Serialize>(changes: &U) -> Result<Document, bson::ser::Error> {
+    Ok(doc! { "$set": bson::to_document(changes)? })
+}
+
+fn update_result(result: mongodb::results::UpdateResult) -> UpdateResult {
+    UpdateResult {
+        matched: result.matched_count,
+        modified: result.modified_count,
+    }
+}
+
+impl From<mongodb::error::Error> for crate::error::Error {
+    fn from(err: mongodb::error::Error) -> Self {
+        // 11000 is mongo's duplicate key error
+        let duplicate = match &*err.kind {
+            ErrorKind::Write(WriteFailure::WriteError(write_error)) => write_error.code == 11000,
+            ErrorKind::BulkWrite(failure) => failure.write_errors.iter().flatten().any(|write_error| write_error.code == 11000),
+            _ => false,
+        };
+        if duplicate {
+            crate::error::Error::Conflict("a resource with this key already exists".to_string())
+        } else {
+            crate::error::Error::backend(err)
+        }
+    }
+}
+
+const DEFAULT_DATABASE: &str = "app";
+
+// per-collection defaults, anything left unset falls back to the client's (which a connection string may set)
+#[derive(Debug, Clone, Default)]
+pub struct CollectionSettings {
+    pub read_concern: Option<ReadConcern>,
+    pub write_concern: Option<WriteConcern>,
+    // read preference or a custom server selection predicate
+    pub selection_criteria: Option<SelectionCriteria>,
+    // applied to queries, updates, deletes and counts
+    pub collation: Option<Collation>,
+}
+
+impl CollectionSettings {
+    // each setting that is unset here is taken from `fallback`
+    fn or(&self, fallback: &CollectionSettings) -> CollectionSettings {
+        CollectionSettings {
+            read_concern: self.read_concern.clone().or_else(|| fallback.read_concern.clone()),
+            write_concern: self.write_concern.clone().or_else(|| fallback.write_concern.clone()),
+            selection_criteria: self.selection_criteria.clone().or_else(|| fallback.selection_criteria.clone()),
+            collation: self.collation.clone().or_else(|| fallback.collation.clone()),
+        }
     }
 }
 
 pub struct DbMongo {
     pub client: Client,
+    // every collection lives in this database
+    pub database: String,
+    defaults: CollectionSettings,
+    // overrides of `defaults` by collection name
+    collections: HashMap<String, CollectionSettings>,
+}
+
+impl DbMongo {
+    // use an already configured client, collections go to `database` with the client's defaults
+    pub fn new<S: Into<String>>(client: Client, database: S) -> Self {
+        DbMongo { client, database: database.into(), defaults: CollectionSettings::default(), collections: HashMap::new() }
+    }
+
+    pub fn builder() -> DbMongoBuilder {
+        DbMongoBuilder::default()
+    }
+
+    pub fn db(&self) -> mongodb::Database {
+        self.client.database(&self.database)
+    }
+
+    pub fn settings(&self, collection: &str) -> CollectionSettings {
+        match self.collections.get(collection) {
+            Some(settings) => settings.or(&self.defaults),
+            None => self.defaults.clone(),
+        }
+    }
+
+    fn collection<T>(&self, name: &str) -> Collection<T> {
+        let settings = self.settings(name);
+        let options = CollectionOptions::builder()
+            .read_concern(settings.read_concern)
+            .write_concern(settings.write_concern)
+            .selection_criteria(settings.selection_criteria)
+            .build();
+        self.db().collection_with_options(name, options)
+    }
+
+    fn collation(&self, collection: &str) -> Option<Collation> {
+        self.settings(collection).collation
+    }
+
+    // reserve `count` integer ids for a collection, counted in the `rsweb_sequences` collection so
+    // every process shares them, returns the first one
+    async fn reserve_ids(&self, collection: &str, count: i64) -> Result<i64, mongodb::error::Error> {
+        let options = FindOneAndUpdateOptions::builder().upsert(true).return_document(ReturnDocument::After).build();
+        let counter = self.collection::<Document>("rsweb_sequences")
+            .find_one_and_update(doc! { "_id": collection }, doc! { "$inc": { "last": count } }, options)
+            .await?;
+        let last = counter.and_then(|counter| counter.get_i64("last").ok()).unwrap_or(count);
+        Ok(last - count + 1)
+    }
+
+    // round trip to the server, for readiness probes
+    pub async fn ping(&self) -> Result<(), mongodb::error::Error> {
+        self.db().run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+}
+
+// DbMongo from a connection string and the RSWEB_MONGO_* env vars, settings made here win over both
+#[derive(Debug, Clone, Default)]
+pub struct DbMongoBuilder {
+    uri: Option<String>,
+    database: Option<String>,
+    defaults: CollectionSettings,
+    collections: HashMap<String, CollectionSettings>,
+}
+
+impl DbMongoBuilder {
+    // e.g. `mongodb://localhost:27017/films?w=majority&readPreference=secondaryPreferred`,
+    // the path names the database and the query string sets the client's concerns and read preference
+    pub fn uri<S: Into<String>>(mut self, uri: S) -> Self {
+        self.uri = Some(uri.into());
+        self
+    }
+
+    pub fn database<S: Into<String>>(mut self, database: S) -> Self {
+        self.database = Some(database.into());
+        self
+    }
+
+    pub fn read_concern(mut self, read_concern: ReadConcern) -> Self {
+        self.defaults.read_concern = Some(read_concern);
+        self
+    }
+
+    pub fn write_concern(mut self, write_concern: WriteConcern) -> Self {
+        self.defaults.write_concern = Some(write_concern);
+        self
+    }
+
+    pub fn read_preference(mut self, read_preference: ReadPreference) -> Self {
+        self.defaults.selection_criteria = Some(SelectionCriteria::ReadPreference(read_preference));
+        self
+    }
+
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.defaults.collation = Some(collation);
+        self
+    }
+
+    // settings for a single collection, what they leave unset comes from the defaults above
+    pub fn collection<S: Into<String>>(mut self, name: S, settings: CollectionSettings) -> Self {
+        self.collections.insert(name.into(), settings);
+        self
+    }
+
+    // reads RSWEB_MONGO_URI, RSWEB_MONGO_DB and RSWEB_MONGO_COLLATION (a locale such as `en`),
+    // without overriding what was already set on the builder
+    pub fn from_env(mut self) -> Self {
+        if self.uri.is_none() {
+            self.uri = std::env::var("RSWEB_MONGO_URI").ok();
+        }
+        if self.database.is_none() {
+            self.database = std::env::var("RSWEB_MONGO_DB").ok();
+        }
+        if self.defaults.collation.is_none() {
+            self.defaults.collation = std::env::var("RSWEB_MONGO_COLLATION").ok()
+                .map(|locale| Collation::builder().locale(locale).build());
+        }
+        self
+    }
+
+    // parses the connection string, connecting happens lazily on the first operation
+    pub async fn build(self) -> Result<DbMongo, mongodb::error::Error> {
+        let options = ClientOptions::parse(self.uri.as_deref().unwrap_or("mongodb://localhost:27017")).await?;
+        let database = self.database
+            .or_else(|| options.default_database.clone())
+            .unwrap_or_else(|| DEFAULT_DATABASE.to_string());
+        Ok(DbMongo {
+            client: Client::with_options(options)?,
+            database,
+            defaults: self.defaults,
+            collections: self.collections,
+        })
+    }
 }
 
 #[async_trait]
@@ -27,78 +297,236 @@ impl Database for DbMongo {
     type Error = mongodb::error::Error;
 
     async fn retrieve_one<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter) -> Result<Option<T>, Self::Error> {
-        let result = self.client
-            .database("app")
-            .collection(&*table_name)
-            .find_one(Some(filter), None)
-            .await;
-
+        let options = FindOneOptions::builder().collation(self.collation(&table_name)).build();
+        let found = self.collection::<Document>(&table_name)
+            .find_one(Some(filter), options)
+            .await?;
+        match found {
+            Some(document) => Ok(Some(bson::from_bson(Bson::Document(document))?)),
+            None => Ok(None),
+        }
+    }
 
-        match result {
-            Ok(resp) => {
-                match resp {
-                    None => { Ok(None) }
-                    Some(doc) => {
-                        match bson::from_bson(bson::Bson::Document(doc)) {
-                            Ok(o) => { Ok(o) }
-                            Err(err) => { Err(Self::Error::from(err)) }
-                        }
-                    }
-                }
+    async fn retrieve_many<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, options: RetrieveOptions) -> Result<Vec<T>, Self::Error> {
+        let mut find_options = FindOptions::default();
+        find_options.limit = options.limit.map(|limit| limit as i64);
+        find_options.skip = options.skip;
+        find_options.collation = self.collation(&table_name);
+        if !options.sort.is_empty() {
+            let mut sort = Document::new();
+            for (key, order) in options.sort {
+                sort.insert(key, if order == SortOrder::Ascending { 1 } else { -1 });
             }
-            Err(bad) => {
-                Err(bad)
+            find_options.sort = Some(sort);
+        }
+        if let Some(fields) = options.projection {
+            let mut projection = Document::new();
+            for key in fields {
+                projection.insert(key, 1);
             }
+            find_options.projection = Some(projection);
         }
-    }
 
-    async fn retrieve_many<T: Send + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter) -> Result<Vec<T>, Self::Error> {
-        let mut cursor = self.client
-            .database("app")
-            .collection(&*table_name)
-            .find(Some(filter), None)
-            .await.unwrap();
+        let mut cursor = self.collection::<Document>(&table_name)
+            .find(Some(filter), find_options)
+            .await?;
 
         let mut res: Vec<T> = vec![];
 
-        while let Ok(Some(item)) = cursor.try_next().await {
-            println!("next item: {}", item);
-            match bson::from_bson(bson::Bson::Document(item)) {
-                Ok(loaded) => {
-                    res.push(loaded);
+        while let Some(item) = cursor.try_next().await? {
+            res.push(bson::from_bson(Bson::Document(item))?);
+        }
+        Ok(res)
+    }
+
+    async fn insert_one<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, item: T) -> Result<Id, Self::Error> {
+        let mut document = bson::to_document(&item)?;
+        if !document.contains_key("_id") && Id::INTEGER {
+            document.insert("_id", self.reserve_ids(&table_name, 1).await?);
+        }
+        let result = self.collection::<Document>(&table_name)
+            .insert_one(document, None)
+            .await?;
+        Ok(bson::from_bson(result.inserted_id)?)
+    }
+
+    async fn insert_many<T: Send + Sync + Serialize + DeserializeOwned, Id: ResourceId>(&self, table_name: String, items: Vec<T>, options: InsertManyOptions) -> Result<Vec<Result<Id, crate::error::Error>>, Self::Error> {
+        if items.is_empty() {
+            // the driver rejects empty batches
+            return Ok(vec![]);
+        }
+        // ids are assigned up front, a failed batch doesn't tell which ones the server made up
+        let mut documents = vec![];
+        for item in items {
+            documents.push(bson::to_document(&item)?);
+        }
+        let missing = documents.iter().filter(|document| !document.contains_key("_id")).count() as i64;
+        let mut next = if missing > 0 && Id::INTEGER { Some(self.reserve_ids(&table_name, missing).await?) } else { None };
+        for document in documents.iter_mut().filter(|document| !document.contains_key("_id")) {
+            match next.as_mut() {
+                Some(next) => {
+                    document.insert("_id", *next);
+                    *next += 1;
                 }
-                Err(err) => {
-                    return Err(Self::Error::from(err));
+                None => {
+                    document.insert("_id", bson::oid::ObjectId::new());
                 }
             }
         }
-        return Ok(res);
-    }
+        let ids: Vec<Bson> = documents.iter().map(|document| document.get("_id").cloned().unwrap_or(Bson::Null)).collect();
 
-    async fn insert_one<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, item: T) -> Result<u32, Self::Error> {
-        match self.client
-            .database("app")
-            .collection(&*table_name)
-            .insert_one(item, None)
-            .await {
-            Ok(result) => {
-                match result.inserted_id {
-                    Bson::Int32(id) => {
-                        Ok(id as u32)
-                    }
-                    _ => {
-                        println!("{}", result.inserted_id);
-                        panic!("Object ID isn't valid")
+        let mut failed = HashMap::new();
+        let inserted = self.collection::<Document>(&table_name)
+            .insert_many(documents, mongodb::options::InsertManyOptions::builder().ordered(options.ordered).build())
+            .await;
+        if let Err(err) = inserted {
+            match &*err.kind {
+                // the write errors carry the index of their item, the other items went in
+                ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => {
+                    for write_error in failure.write_errors.iter().flatten() {
+                        let item_error = if write_error.code == 11000 {
+                            crate::error::Error::Conflict("a resource with this key already exists".to_string())
+                        } else {
+                            crate::error::Error::backend(std::io::Error::other(write_error.message.clone()))
+                        };
+                        failed.insert(write_error.index, item_error);
                     }
                 }
+                _ => return Err(err),
             }
-            Err(err) => {
-                Err(err)
+        }
+        // an ordered batch stops at its first failure
+        let attempted = match failed.keys().min() {
+            Some(first) if options.ordered => first + 1,
+            _ => ids.len(),
+        };
+        let mut results = vec![];
+        for (i, id) in ids.into_iter().enumerate().take(attempted) {
+            results.push(match failed.remove(&i) {
+                Some(err) => Err(err),
+                None => bson::from_bson(id).map_err(crate::error::Error::backend),
+            });
+        }
+        Ok(results)
+    }
+
+    async fn update_one<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error> {
+        let update = set_update(&changes)?;
+        let options = UpdateOptions::builder().collation(self.collation(&table_name)).build();
+        let result = self.collection::<Document>(&table_name)
+            .update_one(filter, update, options)
+            .await?;
+        Ok(update_result(result))
+    }
+
+    async fn update_many<U: Send + Sync + Serialize>(&self, table_name: String, filter: Self::Filter, changes: U) -> Result<UpdateResult, Self::Error> {
+        let update = set_update(&changes)?;
+        let options = UpdateOptions::builder().collation(self.collation(&table_name)).build();
+        let result = self.collection::<Document>(&table_name)
+            .update_many(filter, update, options)
+            .await?;
+        Ok(update_result(result))
+    }
+
+    async fn replace_one<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, filter: Self::Filter, item: T) -> Result<UpdateResult, Self::Error> {
+        let options = ReplaceOptions::builder().collation(self.collation(&table_name)).build();
+        let result = self.collection::<T>(&table_name)
+            .replace_one(filter, item, options)
+            .await?;
+        Ok(update_result(result))
+    }
+
+    async fn delete_one(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error> {
+        let options = DeleteOptions::builder().collation(self.collation(&table_name)).build();
+        let result = self.collection::<Document>(&table_name)
+            .delete_one(filter, options)
+            .await?;
+        Ok(DeleteResult { deleted: result.deleted_count })
+    }
+
+    async fn delete_many(&self, table_name: String, filter: Self::Filter) -> Result<DeleteResult, Self::Error> {
+        let options = DeleteOptions::builder().collation(self.collation(&table_name)).build();
+        let result = self.collection::<Document>(&table_name)
+            .delete_many(filter, options)
+            .await?;
+        Ok(DeleteResult { deleted: result.deleted_count })
+    }
+
+    async fn count(&self, table_name: String, filter: Self::Filter) -> Result<u64, Self::Error> {
+        let options = CountOptions::builder().collation(self.collation(&table_name)).build();
+        self.collection::<Document>(&table_name)
+            .count_documents(filter, options)
+            .await
+    }
+}
+
+impl DbMongo {
+    // the collection's current validator, None if it has none or doesn't exist
+    async fn validator(&self, collection: &str) -> Result<Option<Document>, mongodb::error::Error> {
+        let mut specs = self.db()
+            .list_collections(doc! { "name": collection }, None)
+            .await?;
+        Ok(specs.try_next().await?.and_then(|spec| spec.options.validator))
+    }
+}
+
+fn validator_for(fields: &[crate::application::Field]) -> Value {
+    json!({ "$jsonSchema": json_schema(fields) })
+}
+
+fn to_validator(validator: &Value) -> Document {
+    match to_bson(validator.clone()) {
+        Bson::Document(validator) => validator,
+        _ => Document::new(),
+    }
+}
+
+// collections are schemaless, added fields are covered by an updated validator
+#[async_trait]
+impl Migrate for DbMongo {
+    async fn plan(&self, schemas: &[Schema]) -> Result<Vec<MigrationStep>, Self::Error> {
+        let database = self.db();
+        let existing = database.list_collection_names(None).await?;
+        let mut steps = vec![];
+        for schema in schemas {
+            if !existing.contains(&schema.collection) {
+                steps.extend(schema.create_steps());
+                continue;
             }
+            let validator = validator_for(&schema.fields);
+            if self.validator(&schema.collection).await? != Some(to_validator(&validator)) {
+                steps.push(MigrationStep::SetValidator { collection: schema.collection.clone(), validator });
+            }
+            let indexes = database.collection::<Document>(&schema.collection).list_index_names().await?;
+            steps.extend(schema.missing_indexes(&indexes));
         }
+        Ok(steps)
     }
 
-    async fn insert_many<T: Send + Sync + Serialize + DeserializeOwned>(&self, table_name: String, items: Vec<T>) -> Result<Vec<u32>, Self::Error> {
-        todo!()
+    async fn apply(&self, step: &MigrationStep) -> Result<(), Self::Error> {
+        let database = self.db();
+        match step {
+            MigrationStep::CreateCollection(schema) => {
+                let options = CreateCollectionOptions::builder()
+                    .validator(to_validator(&validator_for(&schema.fields)))
+                    .build();
+                database.create_collection(&schema.collection, options).await?;
+            }
+            MigrationStep::AddField { .. } => {}
+            MigrationStep::CreateIndex { collection, field, unique } => {
+                let options = IndexOptions::builder()
+                    .name(index_name(collection, field))
+                    .unique(*unique)
+                    .build();
+                let mut keys = Document::new();
+                keys.insert(field.clone(), 1);
+                let index = IndexModel::builder().keys(keys).options(options).build();
+                database.collection::<Document>(collection).create_index(index, None).await?;
+            }
+            MigrationStep::SetValidator { collection, validator } => {
+                database.run_command(doc! { "collMod": collection, "validator": to_validator(validator) }, None).await?;
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use serde_json::Value;
+
+// everything a route handler can fail with, rendered as a JSON error response
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    Forbidden,
+    Unauthorized,
+    MethodNotAllowed,
+    // malformed input, with a message per offending field or query param
+    BadRequest { message: String, fields: HashMap<String, String> },
+    Conflict(String),
+    // the body parsed but broke validation rules, with a message per field
+    Unprocessable(HashMap<String, String>),
+    // the data layer failed, details are logged but not sent to the client
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    pub fn bad_request<M: Into<String>>(message: M) -> Self {
+        Error::BadRequest { message: message.into(), fields: HashMap::new() }
+    }
+
+    // bad request blaming a single field or query param
+    pub fn invalid<F: Into<String>, M: Into<String>>(field: F, message: M) -> Self {
+        let mut fields = HashMap::new();
+        fields.insert(field.into(), message.into());
+        Error::BadRequest { message: "invalid input".to_string(), fields }
+    }
+
+    pub fn backend<E: std::error::Error + Send + Sync + 'static>(err: E) -> Self {
+        Error::Backend(Box::new(err))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Error::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // machine-readable name of the variant
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound => "not_found",
+            Error::Forbidden => "forbidden",
+            Error::Unauthorized => "unauthorized",
+            Error::MethodNotAllowed => "method_not_allowed",
+            Error::BadRequest { .. } => "bad_request",
+            Error::Conflict(_) => "conflict",
+            Error::Unprocessable(_) => "validation_failed",
+            Error::Backend(_) => "backend",
+        }
+    }
+
+    fn fields(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Error::BadRequest { fields, .. } if !fields.is_empty() => Some(fields),
+            Error::Unprocessable(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    // `{"error": {"status", "code", "message", "fields"}}`, or an RFC 7807 problem document if `problem` is set
+    pub fn into_response(self, problem: bool) -> Response<Body> {
+        if let Error::Backend(err) = &self {
+            eprintln!("[webf] data layer error: {}", err);
+        }
+        let status = self.status();
+        let (content_type, body) = if problem {
+            let mut body = json!({
+                "type": "about:blank",
+                "title": status.canonical_reason().unwrap_or(""),
+                "status": status.as_u16(),
+                "detail": self.to_string(),
+            });
+            if let Some(fields) = self.fields() {
+                body["errors"] = json!(fields);
+            }
+            ("application/problem+json", body)
+        } else {
+            let mut error = json!({
+                "status": status.as_u16(),
+                "code": self.code(),
+                "message": self.to_string(),
+            });
+            if let Some(fields) = self.fields() {
+                error["fields"] = json!(fields);
+            }
+            ("application/json", json!({ "error": error }))
+        };
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "resource not found"),
+            Error::Forbidden => write!(f, "access denied"),
+            Error::Unauthorized => write!(f, "authentication required"),
+            Error::MethodNotAllowed => write!(f, "method not allowed"),
+            Error::BadRequest { message, .. } => write!(f, "{}", message),
+            Error::Conflict(message) => write!(f, "{}", message),
+            Error::Unprocessable(_) => write!(f, "validation failed"),
+            Error::Backend(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::bad_request(err.to_string())
+    }
+}
+
+// the client asked for RFC 7807 errors through the Accept header
+pub fn wants_problem_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("application/problem+json"))
+        .unwrap_or(false)
+}
+
+// serialize a value the server produced itself, a failure here is a bug rather than bad input
+pub(crate) fn to_json<T: serde::Serialize>(value: &T) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(Error::backend)
+}
@@ -1,53 +1,371 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::fmt::{Debug, Formatter};
-use std::net::SocketAddr;
+use std::marker::PhantomData;
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
 
 use async_trait::async_trait;
-use futures::future::BoxFuture;
-use futures::TryStreamExt;
-use hyper::{Body, Error, Method, Request, Response, Server, StatusCode};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use hyper::server::conn::AddrIncoming;
-use mongodb::bson;
-use mongodb::bson::Bson::DateTime;
-use mongodb::bson::doc;
 use routerify::{RouterBuilder, RouterService};
 use routerify::ext::RequestExt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json::Value;
 
-use crate::application::{Database, Fields, Filter};
+use crate::application::{Database, Field, Fields, FieldType, Filter, find_field, InsertManyOptions, matches_all, merge_patch, Op, Query, QueryFilter, RetrieveOptions, SortOrder, strip_private, strip_server_managed};
+use crate::error::{Error, to_json};
 use crate::frontend_http::MapOrStruct::{Map, Struct};
+use crate::ids::ResourceId;
+use crate::middleware::{self, Middleware, RouteInfo, Stack};
+use crate::openapi::{RouteDoc, RouteKind, type_name};
+use crate::policy::{Access, Action, Policy};
+use crate::relations::{expand, NoRelations, Related};
+use crate::server::ServerConfig;
+use crate::validate::ValidationErrors;
 
 #[async_trait]
 pub trait Route<R, S> where R: Serialize + Send + Sync, S: Context + Send + Sync {
-    async fn generate_context(&self, request: Request<Body>) -> S;
-    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible>;
-    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible>;
+    async fn generate_context(&self, request: Request<Body>) -> Result<S, Error>;
+    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
+    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
+    async fn handler_put<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
+    async fn handler_patch<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
+    async fn handler_delete<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
     fn methods(&self) -> &Vec<Method>;
     fn path(&self) -> &String;
+
+    // how the route shows up in the OpenAPI document, None leaves it out
+    fn describe(&self) -> Option<RouteDoc> {
+        None
+    }
 }
 
 pub enum MapOrStruct<T: Serialize + Send + Sync> {
     Map(HashMap<String, Value>), Struct(T)
 }
 
+// the filtered view of a resource as it is sent out
+fn view<T: Serialize + Send + Sync>(fields: &[Field], filtered: MapOrStruct<T>) -> Result<HashMap<String, Value>, Error> {
+    let mut map = match filtered {
+        Map(map) => map,
+        Struct(f) => serde_json::from_value(to_json(&f)?).map_err(Error::backend)?,
+    };
+    strip_private(fields, &mut map);
+    Ok(map)
+}
+
+fn render<T: Serialize + Send + Sync>(fields: &[Field], filtered: MapOrStruct<T>) -> Result<Response<Body>, Error> {
+    Ok(json_response(StatusCode::OK, &to_json(&view(fields, filtered)?)?))
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn relation_names<R: Related<S>, S: Context + Send + Sync>() -> Vec<String> {
+    R::relations().into_iter().map(|relation| relation.name).collect()
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+}
+
+fn malformed_id<Id: ResourceId>() -> &'static str {
+    if Id::INTEGER { "expected an integer id" } else { "malformed id" }
+}
+
+// build a filter matching the `:id` path param
+fn id_filter<Id: ResourceId, F: Filter>(req: &Request<Body>) -> Result<F, Error> {
+    let raw = req.param("id").ok_or(Error::NotFound)?;
+    let parsed = Id::parse(raw).ok_or_else(|| Error::invalid("id", malformed_id::<Id>()))?;
+    let mut filter = F::default();
+    filter.insert("_id", parsed.to_value());
+    Ok(filter)
+}
+
+// the parent a nested collection is restricted to, taken from the request path
+struct Parent<P, S> {
+    foreign_key: String,
+    parent_id: Value,
+    marker: PhantomData<fn(&P, &S)>,
+}
+
+impl<P, S> Parent<P, S> where P: DataResource + Protected<S> + Send + Sync, S: Context + Send + Sync {
+    fn from_request(req: &Request<Body>, param: &str, foreign_key: &str) -> Result<Self, Error> {
+        let raw = req.param(param).ok_or(Error::NotFound)?;
+        let parent_id = P::Id::parse(raw).ok_or_else(|| Error::invalid(param, malformed_id::<P::Id>()))?.to_value();
+        Ok(Parent { foreign_key: foreign_key.to_string(), parent_id, marker: PhantomData })
+    }
+
+    // the parent has to exist and be readable under its own policy
+    async fn check<DB: Database + Send + Sync>(&self, data_layer: &DB, ctx: &S) -> Result<(), Error> {
+        let mut filter = DB::Filter::default();
+        filter.insert("_id", self.parent_id.clone());
+        let parent: P = load(data_layer, filter).await?;
+        P::policy().access(ctx, Action::Read).check(&to_json(&parent)?)
+    }
+
+    // children are created under the parent whatever the body says
+    fn assign(&self, item: &mut Value) {
+        if let Some(item) = item.as_object_mut() {
+            item.insert(self.foreign_key.clone(), self.parent_id.clone());
+        }
+    }
+}
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 1000;
+// query params of CollectionRoute::handler_get that aren't field filters
+const PAGE_PARAMS: [&str; 7] = ["limit", "offset", "after", "sort", "fields", "count", "expand"];
+
+// `limit`, `offset`, `after`, `sort`, `fields` and `count` query params of a collection request
+struct Page<Id: ResourceId> {
+    limit: u64,
+    offset: u64,
+    after: Option<Id>,
+    sort: Vec<(String, SortOrder)>,
+    fields: Option<Vec<String>>,
+    count: bool,
+}
+
+impl<Id: ResourceId> Page<Id> {
+    fn from_params(params: &HashMap<String, String>, fields: &[Field]) -> Result<Page<Id>, Error> {
+        let sortable = |name: &str| name == "_id" || find_field(fields, name).map(|field| field.sortable).unwrap_or(false);
+        let visible = |name: &str| name == "_id" || find_field(fields, name).map(|field| !field.is_private()).unwrap_or(false);
+        let limit = match params.get("limit") {
+            Some(limit) => limit.parse().ok().filter(|limit| *limit > 0 && *limit <= MAX_PAGE_SIZE)
+                .ok_or_else(|| Error::invalid("limit", format!("expected a number from 1 to {}", MAX_PAGE_SIZE)))?,
+            None => DEFAULT_PAGE_SIZE,
+        };
+        let offset = match params.get("offset") {
+            Some(offset) => offset.parse().map_err(|_| Error::invalid("offset", "expected a number"))?,
+            None => 0,
+        };
+        let after = match params.get("after") {
+            Some(after) => Some(Id::parse(after).ok_or_else(|| Error::invalid("after", malformed_id::<Id>()))?),
+            None => None,
+        };
+
+        let mut sort = vec![];
+        if let Some(keys) = params.get("sort") {
+            for key in keys.split(',') {
+                let (name, order) = match key.strip_prefix('-') {
+                    Some(name) => (name, SortOrder::Descending),
+                    None => (key, SortOrder::Ascending),
+                };
+                if !sortable(name) {
+                    return Err(Error::invalid("sort", format!("can't sort by `{}`", name)));
+                }
+                sort.push((name.to_string(), order));
+            }
+        }
+        // a cursor only makes sense when walking the collection in id order
+        if after.is_some() && (offset > 0 || !sort.is_empty()) {
+            return Err(Error::invalid("after", "can't be combined with `offset` or `sort`"));
+        }
+
+        let fields = match params.get("fields") {
+            Some(names) => {
+                let names: Vec<String> = names.split(',').map(String::from).collect();
+                if let Some(name) = names.iter().find(|name| !visible(name)) {
+                    return Err(Error::invalid("fields", format!("unknown field `{}`", name)));
+                }
+                Some(names)
+            }
+            None => None,
+        };
+
+        Ok(Page {
+            limit,
+            offset,
+            after,
+            sort,
+            fields,
+            count: params.get("count").map(|v| v == "true").unwrap_or(false),
+        })
+    }
+
+    // `fields` is pushed down as a projection of top-level fields, with the ones R can't be
+    // deserialized without and the keys `extra` needs, e.g. those of expanded relations
+    fn options(&self, fields: &[Field], extra: &[String]) -> RetrieveOptions {
+        let mut sort = self.sort.clone();
+        if self.after.is_some() {
+            sort.push(("_id".to_string(), SortOrder::Ascending));
+        }
+        let projection = self.fields.as_ref().map(|names| {
+            let mut keys = vec!["_id".to_string()];
+            let selected = names.iter().map(|name| name.split('.').next().unwrap_or(name).to_string());
+            let required = fields.iter().filter(|field| !field.optional).map(|field| field.name.clone());
+            for key in selected.chain(required).chain(extra.iter().cloned()) {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            keys
+        });
+        RetrieveOptions {
+            limit: Some(self.limit),
+            skip: if self.offset > 0 { Some(self.offset) } else { None },
+            sort,
+            projection,
+        }
+    }
+
+    // link to the following page, continuing the cursor if one was used
+    fn next_link(&self, path: &str, params: &HashMap<String, String>, last_id: Option<Id>) -> Option<String> {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        let mut keys: Vec<&String> = params.keys().filter(|key| *key != "offset" && *key != "after").collect();
+        keys.sort();
+        for key in keys {
+            query.append_pair(key, &params[key]);
+        }
+        if self.after.is_some() {
+            query.append_pair("after", &last_id?.to_string());
+        } else {
+            query.append_pair("offset", &(self.offset + self.limit).to_string());
+        }
+        Some(format!("{}?{}", path, query.finish()))
+    }
+}
+
+// whether `keep` selects anything of `key`, a dotted path such as `director.name` prunes the
+// value down to that part of it
+fn keep_selected(keep: &[&str], key: &str, value: &mut Value) -> bool {
+    let mut nested = vec![];
+    for path in keep {
+        if *path == key {
+            return true;
+        }
+        if let Some(rest) = path.strip_prefix(key).and_then(|rest| rest.strip_prefix('.')) {
+            nested.push(rest);
+        }
+    }
+    if nested.is_empty() {
+        return false;
+    }
+    prune(&nested, value);
+    true
+}
+
+// the same for the keys of a nested object, or of each of them in an array
+fn prune(keep: &[&str], value: &mut Value) {
+    match value {
+        Value::Object(map) => map.retain(|key, value| keep_selected(keep, key, value)),
+        Value::Array(items) => {
+            for item in items {
+                prune(keep, item);
+            }
+        }
+        _ => {}
+    }
+}
+
+// build the query for a single `field[op]=raw` param, None if the value doesn't fit the field
+fn param_query(path: &str, field: &Field, op: Op, raw: &str) -> Option<Query> {
+    let value = match op {
+        Op::In | Op::Nin => {
+            let mut values = vec![];
+            for item in raw.split(',') {
+                values.push(coerce(field, item)?);
+            }
+            Value::Array(values)
+        }
+        Op::Exists => Value::Bool(raw.parse().ok()?),
+        Op::Regex | Op::Contains => Value::String(raw.to_string()),
+        _ => coerce(field, raw)?,
+    };
+    Some(Query::field(path).cmp(op, value))
+}
+
+fn coerce(field: &Field, raw: &str) -> Option<Value> {
+    if field.nullable && raw == "null" {
+        return Some(Value::Null);
+    }
+    coerce_type(&field.field_type, raw)
+}
+
+fn coerce_type(field_type: &FieldType, raw: &str) -> Option<Value> {
+    match field_type {
+        FieldType::Int => raw.parse::<i64>().ok().map(Value::from),
+        FieldType::Float => raw.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number),
+        FieldType::Bool => raw.parse::<bool>().ok().map(Value::Bool),
+        FieldType::String => Some(Value::String(raw.to_string())),
+        // matching a single value against an array field checks whether it contains it
+        FieldType::Array(inner) => coerce_type(inner, raw),
+        // whole objects can't be expressed in a query param, filter on `field.nested` instead
+        FieldType::Object(_) => None,
+    }
+}
+
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    req
+        .uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// entry of a bulk create response for an item that wasn't stored
+fn item_error(err: Error) -> Value {
+    if let Error::Backend(inner) = &err {
+        eprintln!("[webf] data layer error: {}", inner);
+    }
+    let mut entry = json!({ "error": err.to_string() });
+    if let Error::Unprocessable(fields) = &err {
+        entry["fields"] = json!(fields);
+    }
+    entry
+}
+
+// separate the body from the request so the rest can still be handed to Context::generate
+async fn split_body(req: Request<Body>) -> Result<(Request<Body>, Value), Error> {
+    let (parts, body) = req.into_parts();
+    let bytes = hyper::body::to_bytes(body).await
+        .map_err(|_| Error::bad_request("error reading body"))?;
+    let body = serde_json::from_slice(&bytes)?;
+    Ok((Request::from_parts(parts, Body::empty()), body))
+}
+
 pub struct SingleRoute<R, S> where R: Serialize + Send + Sync, S: Context + Send + Sync {
     pub path: String,
     pub methods: Vec<Method>,
-    pub check_to_view: fn(&R, &S) -> BoxFuture<'static, bool>,
     pub filter_view_data: fn(&S, R) -> MapOrStruct<R>,
+    // see CollectionRoute::scope, lookups, edits and deletes outside of it answer 404 and
+    // edits can't move an item out of it
+    pub scope: Option<fn(&S, &mut QueryFilter)>,
     // pub filters_get: Vec<fn (&R, &mut S, &HashMap<String, serde_json::value::Value>)>
 }
 
 pub struct CollectionRoute<R, S> where R: Serialize, S: Context {
     pub path: String,
     pub methods: Vec<Method>,
-    pub check_to_view: fn(&S) -> BoxFuture<'static, bool>,
     pub filter_one: fn(&S, R) -> HashMap<String, Value>,
+    // narrows the rows the caller reaches through the route, e.g. to their own with
+    // `filter.insert("user_id", ctx.user_id)`; the conditions go into the query, so pages and
+    // counts stay right, and created items that don't match them are refused
+    pub scope: Option<fn(&S, &mut QueryFilter)>,
+}
+
+// the children of a single parent, e.g. `/users/:user_id/movies` for the movies whose `user_id`
+// is the id in the path; GET only lists them and POST creates them with that id, both only
+// for callers who may read the parent
+pub struct NestedRoute<P, R, S> where P: Serialize, R: Serialize, S: Context {
+    // path param holding the parent's id
+    pub parent_param: String,
+    // field of R that refers to the parent
+    pub foreign_key: String,
+    // path, methods and view of the children
+    pub children: CollectionRoute<R, S>,
+    // the parent resource, whose read policy decides who gets to its children
+    pub parent: PhantomData<P>,
 }
 
 #[async_trait]
@@ -56,39 +374,130 @@ pub trait FrontendExtended<R> where R: Serialize + DeserializeOwned {
     async fn new_with_base(base: R);
 }
 
+impl<R, S> SingleRoute<R, S> where R: DataResource + Fields + Send + Sync, S: Context + Send + Sync {
+    // write an edited resource back and respond with its filtered view
+    async fn save<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, filter: DB::Filter, item: R, ctx: &S) -> Result<Response<Body>, Error> {
+        // the item is moved into the data layer, keep a copy for the response
+        let saved: R = serde_json::from_value(to_json(&item)?).map_err(Error::backend)?;
+        let result = data_layer.replace_one(R::get_collection_name(), filter, item).await.map_err(Into::into)?;
+        if result.matched == 0 {
+            return Err(Error::NotFound);
+        }
+        render(&R::fields(), (self.filter_view_data)(ctx, saved))
+    }
+}
+
+// the conditions of a route's scope for the caller
+fn scope_filter<S>(scope: Option<fn(&S, &mut QueryFilter)>, ctx: &S) -> Option<QueryFilter> {
+    scope.map(|scope| {
+        let mut scoped = QueryFilter::default();
+        scope(ctx, &mut scoped);
+        scoped
+    })
+}
+
+// add the conditions of a route's scope to a query of the data layer
+fn apply_scope<S, F: Filter>(scope: Option<fn(&S, &mut QueryFilter)>, ctx: &S, filter: &mut F) {
+    for query in scope_filter(scope, ctx).map(|scoped| scoped.queries).unwrap_or_default() {
+        filter.add(query);
+    }
+}
+
+// stored items are only reached through queries carrying the scope, see apply_scope; an item
+// about to be written isn't stored yet, so its JSON form is matched against the scope here
+fn check_scope<S>(scope: Option<fn(&S, &mut QueryFilter)>, ctx: &S, item: &Value) -> Result<(), Error> {
+    match scope_filter(scope, ctx) {
+        Some(scoped) if !matches_all(item, &scoped.queries).map_err(Error::backend)? => Err(Error::Forbidden),
+        _ => Ok(()),
+    }
+}
+
+async fn load<R: DataResource + Send, DB: Database + Send + Sync>(data_layer: &DB, filter: DB::Filter) -> Result<R, Error> {
+    data_layer.retrieve_one(R::get_collection_name(), filter).await
+        .map_err(Into::into)?
+        .ok_or(Error::NotFound)
+}
+
 #[async_trait]
-impl<R, S> Route<R, S> for SingleRoute<R, S> where R: DataResource + Fields + Send + Sync , S: Context + Send + Sync{
-    async fn generate_context(&self, request: Request<Body>) -> S {
+impl<R, S> Route<R, S> for SingleRoute<R, S> where R: DataResource + Fields + Protected<S> + Send + Sync , S: Context + Send + Sync{
+    async fn generate_context(&self, request: Request<Body>) -> Result<S, Error> {
         S::generate(request).await
     }
-    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        let mut filter = DB::Filter::default();
+    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.get_expanded::<NoRelations, DB>(data_layer, req).await
+    }
 
-        if let Some(id) = req.param("id") {
-            let parsed: u32 = id.parse().expect("[webf] error parsing id query param");
-            filter.insert("_id", parsed);
-        }
+    async fn handler_post<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
 
-        let ctx = &self.generate_context(req).await;
-        let data = data_layer.retrieve_one(R::get_collection_name(), filter).await;
+    // full replace of the stored resource with the request body
+    async fn handler_put<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let mut filter: DB::Filter = id_filter::<R::Id, _>(&req)?;
+        let (req, mut body) = split_body(req).await?;
+
+        let ctx = &self.generate_context(req).await?;
+        apply_scope(self.scope, ctx, &mut filter);
+        let existing: R = load(&*data_layer, filter.clone()).await?;
+        let access = R::policy().access(ctx, Action::Update);
+        access.check(&to_json(&existing)?)?;
+
+        // server-managed fields keep their stored values
+        strip_server_managed(&R::fields(), &mut body, Some(&to_json(&existing)?));
+        let mut item: R = serde_json::from_value(body)?;
+
+        item.set_id(existing.get_id());
+        item.sanitize_edit_data(ctx);
+        item.validate_data()?;
+        // nor may an edit move it out of the caller's reach, e.g. to another owner
+        let edited = to_json(&item)?;
+        access.check(&edited)?;
+        check_scope(self.scope, ctx, &edited)?;
+        self.save(data_layer, filter, item, ctx).await
+    }
 
-        if let Ok(Some(data)) = data
-        {
-            if !(&self.check_to_view)(&data, &ctx).await {
-                return Ok(Response::new(Body::from("access denied")))
-            }
-            let filtered = (&self.filter_view_data)(&ctx, data);
-            match filtered {
-                Map(map) => { Ok(Response::new(Body::from(serde_json::ser::to_string(&map).unwrap()))) }
-                Struct(f) => { Ok(Response::new(Body::from(serde_json::ser::to_string(&f).unwrap()))) }
-            }
-        } else {
-            Ok(Response::builder().status(404).body(Body::from("")).unwrap())
+    // merge the request body into the stored resource
+    async fn handler_patch<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let mut filter: DB::Filter = id_filter::<R::Id, _>(&req)?;
+        let (req, mut patch) = split_body(req).await?;
+        if !patch.is_object() {
+            return Err(Error::bad_request("expected a JSON object"));
         }
+
+        let ctx = &self.generate_context(req).await?;
+        apply_scope(self.scope, ctx, &mut filter);
+        let existing: R = load(&*data_layer, filter.clone()).await?;
+        let access = R::policy().access(ctx, Action::Update);
+        access.check(&to_json(&existing)?)?;
+
+        let mut merged = to_json(&existing)?;
+        strip_server_managed(&R::fields(), &mut patch, Some(&merged));
+        merge_patch(&mut merged, patch);
+        let mut item: R = serde_json::from_value(merged)?;
+
+        item.set_id(existing.get_id());
+        item.sanitize_edit_data(ctx);
+        item.validate_data()?;
+        // nor may an edit move it out of the caller's reach, e.g. to another owner
+        let edited = to_json(&item)?;
+        access.check(&edited)?;
+        check_scope(self.scope, ctx, &edited)?;
+        self.save(data_layer, filter, item, ctx).await
     }
 
-    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        Ok(Response::builder().status(405).body(Body::from("")).unwrap())
+    async fn handler_delete<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let mut filter: DB::Filter = id_filter::<R::Id, _>(&req)?;
+
+        let ctx = &self.generate_context(req).await?;
+        apply_scope(self.scope, ctx, &mut filter);
+        let existing: R = load(&*data_layer, filter.clone()).await?;
+        R::policy().access(ctx, Action::Delete).check(&to_json(&existing)?)?;
+
+        let result = data_layer.delete_one(R::get_collection_name(), filter).await.map_err(Into::into)?;
+        if result.deleted == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(no_content())
     }
 
     fn methods(&self) -> &Vec<Method> {
@@ -98,98 +507,233 @@ impl<R, S> Route<R, S> for SingleRoute<R, S> where R: DataResource + Fields + Se
     fn path(&self) -> &String {
         &self.path
     }
-}
 
+    fn describe(&self) -> Option<RouteDoc> {
+        self.describe_expanded::<NoRelations>()
+    }
+}
 
 #[async_trait]
-impl<R, S> Route<R, S> for CollectionRoute<R, S> where R: DataResource + Fields + Send + Sync , S: Context + Send + Sync {
-    async fn generate_context(&self, request: Request<Body>) -> S {
-        S::generate(request).await
+impl<R, S> Expandable<R, S> for SingleRoute<R, S> where R: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync {
+    async fn get_expanded<X: Related<S>, DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let mut filter: DB::Filter = id_filter::<R::Id, _>(&req)?;
+        let params = query_params(&req);
+
+        let ctx = &self.generate_context(req).await?;
+        apply_scope(self.scope, ctx, &mut filter);
+        let data: R = load(&*data_layer, filter).await?;
+        R::policy().access(ctx, Action::Read).check(&to_json(&data)?)?;
+        let mut view = view(&R::fields(), (self.filter_view_data)(ctx, data))?;
+        expand::<X, S, DB>(params.get("expand"), std::slice::from_mut(&mut view), &*data_layer, ctx).await?;
+        Ok(json_response(StatusCode::OK, &to_json(&view)?))
     }
-    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        println!("got request");
 
-        let params: HashMap<String, String> = req
-            .uri()
-            .query()
-            .map(|v| {
-                url::form_urlencoded::parse(v.as_bytes())
-                    .into_owned()
-                    .collect()
-            })
-            .unwrap_or_else(HashMap::new);
+    fn describe_expanded<X: Related<S>>(&self) -> Option<RouteDoc> {
+        Some(RouteDoc::of::<R>(&self.path, &self.methods, RouteKind::Single, relation_names::<X, S>()))
+    }
+}
 
-        println!("{:?}", params.iter());
 
-        let mut filter = DB::Filter::default();
-
-        for key in R::fields() {
-            let name = &*key.name;
-            if params.contains_key(name) {
-                println!("{}", name);
-
-                if key.is_num {
-                    let parsed: u32 = params.get(name).unwrap().parse().expect("[webf] error parsing int query param");
-                    filter.insert(name, parsed);
-                } else if key.is_bool {
-                    if key.name == "true" {
-                        filter.insert(key.name, true);
-                    } else if key.name == "false" {
-                        filter.insert(key.name, false);
+impl<R, S> CollectionRoute<R, S> where R: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync {
+    // bulk create from a JSON array, responds with one `{"id"}` or `{"error"}` entry per element
+    // that was attempted
+    async fn create_many<P, DB>(&self, data_layer: Arc<DB>, items: Vec<Value>, options: InsertManyOptions, parent: Option<&Parent<P, S>>, ctx: &S) -> Result<Response<Body>, Error>
+        where P: DataResource + Protected<S> + Send + Sync, DB: Database + Send + Sync
+    {
+        let access = R::policy().access(ctx, Action::Create);
+        let mut results = vec![Value::Null; items.len()];
+        let mut valid = vec![];
+        let mut positions = vec![];
+        let fields = R::fields();
+        for (i, mut item) in items.into_iter().enumerate() {
+            strip_server_managed(&fields, &mut item, None);
+            if let Some(parent) = parent {
+                parent.assign(&mut item);
+            }
+            let checked = serde_json::from_value::<R>(item).map_err(Error::from).and_then(|mut deser| {
+                deser.sanitize_edit_data(ctx);
+                deser.validate_data()?;
+                let created = to_json(&deser)?;
+                access.check(&created)?;
+                check_scope(self.scope, ctx, &created)?;
+                Ok(deser)
+            });
+            match checked {
+                Ok(mut deser) => {
+                    deser.set_id(R::next_id());
+                    valid.push(deser);
+                    positions.push(i);
+                }
+                Err(err) => {
+                    results[i] = item_error(err);
+                    if options.ordered {
+                        // like the data layer, an ordered batch stops at the first failure
+                        results.truncate(i + 1);
+                        break;
                     }
-                } else {
-                    filter.insert(name, params.get(name).unwrap());
                 }
             }
         }
 
-        let ctx = &self.generate_context(req).await;
-        if !(&self.check_to_view)(&ctx).await {
-            return Ok(Response::new(Body::from("access denied")))
+        // items the data layer refused are reported like invalid ones, the others are stored
+        let inserted = data_layer.insert_many::<R, R::Id>(R::get_collection_name(), valid, options).await.map_err(Into::into)?;
+        let attempted = inserted.len();
+        for (i, result) in positions.iter().zip(inserted) {
+            results[*i] = match result {
+                Ok(id) => json!({ "id": id }),
+                Err(err) => item_error(err),
+            };
+        }
+        // an ordered batch the data layer stopped early leaves out the items after the failure
+        if let Some(stopped) = positions.get(attempted) {
+            results.truncate(*stopped);
         }
+        Ok(json_response(StatusCode::OK, &Value::Array(results)))
+    }
 
-        let mut res = data_layer.retrieve_many(R::get_collection_name(), filter).await;
-        match res {
-            Ok(res) => {
-                let mut maps = vec![];
-                for item in res {
-                    maps.push((self.filter_one)(&ctx, item));
-                }
-                match serde_json::to_string(&maps) {
-                    Ok(serialized) => {
-                        Ok(Response::new(Body::from(serialized)))
-                    }
-                    Err(err) => {
-                        Ok(Response::builder().status(500).body(Body::from("error deserializing")).unwrap())
-                    }
-                }
+    // filtered, paginated listing, restricted to the children of `parent` when nested
+    async fn list<P, X, DB>(&self, data_layer: Arc<DB>, req: Request<Body>, parent: Option<Parent<P, S>>) -> Result<Response<Body>, Error>
+        where P: DataResource + Protected<S> + Send + Sync, X: Related<S>, DB: Database + Send + Sync
+    {
+        let params = query_params(&req);
+
+        let mut filter = DB::Filter::default();
+        if let Some(parent) = &parent {
+            filter.add(Query::field(parent.foreign_key.as_str()).eq(parent.parent_id.clone()));
+        }
+
+        let fields = R::fields();
+        for (param, raw) in &params {
+            // `year=1999` is an equality match, `year[gte]=1999` picks the operator
+            let (name, op) = match param.find('[') {
+                Some(start) if param.ends_with(']') => (&param[..start], Op::from_param(&param[start + 1..param.len() - 1])),
+                _ => (&param[..], Some(Op::Eq)),
+            };
+            if PAGE_PARAMS.contains(&name) {
+                continue;
             }
-            Err(_) => {
-                Ok(Response::builder().status(500).body(Body::from("error in data layer")).unwrap())
+            let field = match find_field(&fields, name) {
+                Some(field) => field,
+                None => continue,
+            };
+            if !field.filterable {
+                return Err(Error::invalid(param.as_str(), "field is not filterable"));
             }
+            let op = op.ok_or_else(|| Error::invalid(param.as_str(), "unknown operator"))?;
+            let query = param_query(name, field, op, raw)
+                .ok_or_else(|| Error::invalid(param.as_str(), "value doesn't match the field type"))?;
+            filter.add(query);
         }
-    }
 
-    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        if let Ok(body) = hyper::body::to_bytes(req.into_body()).await {
-            let res : serde_json::Result<R> = serde_json::de::from_slice(&*body.to_vec());
-            if let Ok(mut deser) = res {
-                deser.set_id(Some(std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32));
-                let res = data_layer.insert_one(R::get_collection_name(), deser).await;
-                match res {
-                    Ok(id) => {
-                        Ok(Response::new(Body::from(id.to_string())))
-                    }
-                    Err(_) => {
-                        Ok(Response::builder().status(500).body(Body::from("Error inserting")).unwrap())
-                    }
-                }
-            } else {
-                Ok(Response::new(Body::from("error deserializing")))
-            }
+        let page = Page::<R::Id>::from_params(&params, &fields)?;
+        let path = req.uri().path().to_string();
+
+        let ctx = &S::generate(req).await?;
+        if let Some(parent) = &parent {
+            parent.check(&*data_layer, ctx).await?;
+        }
+        R::policy().access(ctx, Action::List).restrict(&mut filter)?;
+        apply_scope(self.scope, ctx, &mut filter);
+
+        // the total ignores the cursor so it stays the same across pages
+        let total = if page.count {
+            Some(data_layer.count(R::get_collection_name(), filter.clone()).await.map_err(Into::into)?)
         } else {
-            Ok(Response::new(Body::from("internal server error")))
+            None
+        };
+        if let Some(after) = &page.after {
+            filter.add(Query::field("_id").gt(after.to_value()));
+        }
+
+        let expanded = params.get("expand");
+        let relations: Vec<&str> = expanded.map(|names| names.split(',').collect()).unwrap_or_default();
+        let relation_keys: Vec<String> = X::relations().into_iter()
+            .filter(|relation| relations.contains(&relation.name.as_str()))
+            .map(|relation| relation.local_key)
+            .collect();
+        let res: Vec<R> = data_layer.retrieve_many(R::get_collection_name(), filter, page.options(&fields, &relation_keys)).await.map_err(Into::into)?;
+        let full = res.len() as u64 == page.limit;
+        let last_id = res.last().and_then(|item| item.get_id());
+        let mut maps = vec![];
+        for item in res {
+            let mut map = (self.filter_one)(ctx, item);
+            strip_private(&fields, &mut map);
+            maps.push(map);
         }
+        // all items share one query per relation, expanded before `fields` may drop the keys
+        expand::<X, S, DB>(expanded, &mut maps, &*data_layer, ctx).await?;
+        if let Some(keep) = &page.fields {
+            let keep: Vec<&str> = keep.iter().map(String::as_str).chain(relations.iter().cloned()).collect();
+            for map in &mut maps {
+                map.retain(|key, value| keep_selected(&keep, key, value));
+            }
+        }
+        let next = if full { page.next_link(&path, &params, last_id) } else { None };
+        let mut envelope = json!({ "data": maps, "next": next });
+        if let Some(total) = total {
+            envelope["total"] = json!(total);
+        }
+        Ok(json_response(StatusCode::OK, &envelope))
+    }
+
+    async fn create<P, DB>(&self, data_layer: Arc<DB>, req: Request<Body>, parent: Option<Parent<P, S>>) -> Result<Response<Body>, Error>
+        where P: DataResource + Protected<S> + Send + Sync, DB: Database + Send + Sync
+    {
+        let options = InsertManyOptions {
+            ordered: query_params(&req).get("ordered").map(|v| v != "false").unwrap_or(true),
+        };
+        let (req, body) = split_body(req).await?;
+        let ctx = &S::generate(req).await?;
+        if let Some(parent) = &parent {
+            parent.check(&*data_layer, ctx).await?;
+        }
+        let access = R::policy().access(ctx, Action::Create);
+        if access == Access::Denied {
+            return Err(Error::Forbidden);
+        }
+        let mut value = match body {
+            Value::Array(items) => return self.create_many(data_layer, items, options, parent.as_ref(), ctx).await,
+            value => value,
+        };
+        strip_server_managed(&R::fields(), &mut value, None);
+        if let Some(parent) = &parent {
+            parent.assign(&mut value);
+        }
+        let mut deser: R = serde_json::from_value(value)?;
+        deser.sanitize_edit_data(ctx);
+        deser.validate_data()?;
+        let created = to_json(&deser)?;
+        access.check(&created)?;
+        check_scope(self.scope, ctx, &created)?;
+        deser.set_id(R::next_id());
+        let id: R::Id = data_layer.insert_one(R::get_collection_name(), deser).await.map_err(Into::into)?;
+        Ok(json_response(StatusCode::CREATED, &json!({ "id": id })))
+    }
+}
+
+#[async_trait]
+impl<R, S> Route<R, S> for CollectionRoute<R, S> where R: DataResource + Fields + Protected<S> + Send + Sync , S: Context + Send + Sync {
+    async fn generate_context(&self, request: Request<Body>) -> Result<S, Error> {
+        S::generate(request).await
+    }
+    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.get_expanded::<NoRelations, DB>(data_layer, req).await
+    }
+
+    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.create::<R, DB>(data_layer, req, None).await
+    }
+
+    async fn handler_put<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
+
+    async fn handler_patch<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
+
+    async fn handler_delete<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
     }
 
     fn methods(&self) -> &Vec<Method> {
@@ -199,101 +743,271 @@ impl<R, S> Route<R, S> for CollectionRoute<R, S> where R: DataResource + Fields
     fn path(&self) -> &String {
         &self.path
     }
+
+    fn describe(&self) -> Option<RouteDoc> {
+        self.describe_expanded::<NoRelations>()
+    }
+}
+
+#[async_trait]
+impl<R, S> Expandable<R, S> for CollectionRoute<R, S> where R: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync {
+    async fn get_expanded<X: Related<S>, DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.list::<R, X, DB>(data_layer, req, None).await
+    }
+
+    fn describe_expanded<X: Related<S>>(&self) -> Option<RouteDoc> {
+        Some(RouteDoc::of::<R>(&self.path, &self.methods, RouteKind::Collection, relation_names::<X, S>()))
+    }
+}
+
+#[async_trait]
+impl<P, R, S> Route<R, S> for NestedRoute<P, R, S>
+    where P: DataResource + Protected<S> + Send + Sync, R: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync
+{
+    async fn generate_context(&self, request: Request<Body>) -> Result<S, Error> {
+        S::generate(request).await
+    }
+
+    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.get_expanded::<NoRelations, DB>(data_layer, req).await
+    }
+
+    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let parent = Parent::from_request(&req, &self.parent_param, &self.foreign_key)?;
+        self.children.create::<P, DB>(data_layer, req, Some(parent)).await
+    }
+
+    async fn handler_put<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
+
+    async fn handler_patch<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
+
+    async fn handler_delete<DB: Database + Send + Sync>(&self, _data_layer: Arc<DB>, _req: Request<Body>) -> Result<Response<Body>, Error> {
+        Err(Error::MethodNotAllowed)
+    }
+
+    fn methods(&self) -> &Vec<Method> {
+        &self.children.methods
+    }
+
+    fn path(&self) -> &String {
+        &self.children.path
+    }
+
+    fn describe(&self) -> Option<RouteDoc> {
+        self.describe_expanded::<NoRelations>()
+    }
+}
+
+#[async_trait]
+impl<P, R, S> Expandable<R, S> for NestedRoute<P, R, S>
+    where P: DataResource + Protected<S> + Send + Sync, R: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync
+{
+    async fn get_expanded<X: Related<S>, DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let parent = Parent::from_request(&req, &self.parent_param, &self.foreign_key)?;
+        self.children.list::<P, X, DB>(data_layer, req, Some(parent)).await
+    }
+
+    fn describe_expanded<X: Related<S>>(&self) -> Option<RouteDoc> {
+        let kind = RouteKind::Nested { parent_param: self.parent_param.clone(), integer_parent: P::Id::INTEGER };
+        Some(RouteDoc::of::<R>(&self.children.path, &self.children.methods, kind, relation_names::<X, S>()))
+    }
+}
+
+// a route whose GET embeds the relations named in `?expand=`, e.g. `Expanding(SingleRoute { .. })`;
+// it takes the resource to implement Related, as the DataResource derive does, plain routes
+// have no relations to expand
+pub struct Expanding<RT>(pub RT);
+
+// the GET handler and OpenAPI description of a route, with the relations X offers
+#[doc(hidden)]
+#[async_trait]
+pub trait Expandable<R, S> where S: Context + Send + Sync {
+    async fn get_expanded<X: Related<S>, DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error>;
+    fn describe_expanded<X: Related<S>>(&self) -> Option<RouteDoc>;
+}
+
+#[async_trait]
+impl<R, S, RT> Route<R, S> for Expanding<RT>
+    where R: Related<S> + Serialize + Send + Sync, S: Context + Send + Sync, RT: Route<R, S> + Expandable<R, S> + Send + Sync
+{
+    async fn generate_context(&self, request: Request<Body>) -> Result<S, Error> {
+        self.0.generate_context(request).await
+    }
+
+    async fn handler_get<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.0.get_expanded::<R, DB>(data_layer, req).await
+    }
+
+    async fn handler_post<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.0.handler_post(data_layer, req).await
+    }
+
+    async fn handler_put<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.0.handler_put(data_layer, req).await
+    }
+
+    async fn handler_patch<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.0.handler_patch(data_layer, req).await
+    }
+
+    async fn handler_delete<DB: Database + Send + Sync>(&self, data_layer: Arc<DB>, req: Request<Body>) -> Result<Response<Body>, Error> {
+        self.0.handler_delete(data_layer, req).await
+    }
+
+    fn methods(&self) -> &Vec<Method> {
+        self.0.methods()
+    }
+
+    fn path(&self) -> &String {
+        self.0.path()
+    }
+
+    fn describe(&self) -> Option<RouteDoc> {
+        self.0.describe_expanded::<R>()
+    }
 }
 
 
 #[async_trait]
 pub trait Context {
-    async fn generate(req: Request<Body>) -> Self;
+    // an error, e.g. Unauthorized when the caller can't be identified, is the response
+    async fn generate(req: Request<Body>) -> Result<Self, Error> where Self: Sized;
+
+    // what the resources' policies know about the caller: the roles their rules grant to and
+    // the id their owner rules compare with, None for anonymous callers
+    fn roles(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn principal_id(&self) -> Option<Value> {
+        None
+    }
 }
 
 pub trait DataResource: Serialize + DeserializeOwned {
+    type Id: ResourceId;
     fn get_collection_name() -> String;
-    fn get_id(&self) -> Option<u32>;
-    fn set_id(&mut self, id: Option<u32>);
-}
+    fn get_id(&self) -> Option<Self::Id>;
+    fn set_id(&mut self, id: Option<Self::Id>);
 
-#[async_trait]
-pub trait Protected {
-    fn check_to_view<C: Context>(&self, ctx: &C) -> bool {
-        false
+    // id for a newly created resource, None lets the data layer assign one
+    fn next_id() -> Option<Self::Id> {
+        Self::Id::generate()
     }
 
-    fn check_to_edit<C: Context>(&self, ctx: C) -> bool {
-        false
+    // checks incoming data before it is written, nothing to check by default;
+    // the derive runs the `Validate` rules when there are any, see `#[rsweb(validate)]`
+    fn validate_data(&self) -> Result<(), ValidationErrors> {
+        Ok(())
     }
+}
 
-    fn check_to_delete<C: Context>(&self, ctx: C) -> bool {
-        false
+// per-resource authorization, evaluated the same way by every route handling the resource
+pub trait Protected<C: Context> {
+    // who may read, list, create, update and delete the resource, nobody by default
+    fn policy() -> Policy {
+        Policy::new()
     }
 
-    fn sanitize_edit_data<C: Context>(&self, ctx: C) {}
+    // runs on incoming data before it is validated and written
+    fn sanitize_edit_data(&mut self, _ctx: &C) {}
 
-    fn filter_view_data<C: Context>(&self, ctx: C, response: &mut HashMap<String, serde_json::value::Value>) {}
+    fn filter_view_data(&self, _ctx: &C, _response: &mut HashMap<String, serde_json::value::Value>) {}
 }
 
+#[deprecated(note = "use Application::serve, which takes the address from a ServerConfig and shuts down gracefully")]
 pub async fn launch<T: Database>(app: Application<T>) -> Server<AddrIncoming, RouterService<Body, Infallible>> {
     // Create a Service from the router above to handle incoming requests.
     let service = RouterService::new(app.router_builder.build().unwrap()).unwrap();
 
     // The address on which the server will be listening.
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let addr = ServerConfig::default().addr;
 
     // Create a server by passing the created service to `.serve` method.
     let server = Server::bind(&addr).serve(service);
 
     println!("App is running on: {}", addr);
-    return server;
+    server
 }
 
 pub struct Application<T: Database> {
     // pub router_builder: Box<&'static RouterBuilder<Body, Infallible>>,
     pub router_builder: RouterBuilder<Body, Infallible>,
     pub data_source: Arc<T>,
+    // what the added routes look like, for the OpenAPI document
+    pub routes: Vec<RouteDoc>,
+    middleware: Stack,
 }
 
 impl<T> Application<T> where T: Database + 'static + Send + Sync {
+    pub fn new(data_source: T) -> Self {
+        Application {
+            router_builder: RouterBuilder::new(),
+            data_source: Arc::new(data_source),
+            routes: vec![],
+            middleware: Stack::default(),
+        }
+    }
+
+    // wraps every route, in the order added and before any per-route middleware
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.write().unwrap_or_else(|poisoned| poisoned.into_inner()).push(Arc::new(middleware));
+    }
+
     pub fn add_route<R: 'static + DataResource + Send + Sync, S: 'static +  Context + Send + Sync, RT: 'static + Route<R, S> + Send + Sync>
     (&mut self, rt: RT) {
+        self.add_route_with(rt, vec![]);
+    }
+
+    // add a route with middleware of its own, it runs after the global middleware
+    pub fn add_route_with<R: 'static + DataResource + Send + Sync, S: 'static +  Context + Send + Sync, RT: 'static + Route<R, S> + Send + Sync>
+    (&mut self, rt: RT, middleware: Vec<Arc<dyn Middleware>>) {
         let route = Arc::new(rt);
-        for method in route.methods() {
-            match method {
-                &Method::GET => {
-                    println!("[webf] added GET route {}", route.path());
-                    let handler = {
-                        let ds = self.data_source.clone();
-                        let route = route.clone();
-                        move |req| {
-                            let ds = ds.clone();
-                            let route = route.clone();
-                            async move {
-                                route.handler_get(ds, req).await
-                            }
-                        }
-                    };
-                    let mut refer = std::mem::take(&mut self.router_builder);
-                    self.router_builder = refer.get(route.path(), handler);
-                }
-                &Method::POST => {
-                    println!("[webf] added POST route {}", route.path());
-                    let handler = {
-                        let ds = self.data_source.clone();
-                        let route = route.clone();
-                        move |req| {
-                            let ds = ds.clone();
-                            let route = route.clone();
-                            async move {
-                                route.handler_post(ds, req).await
+        let local: Arc<[Arc<dyn Middleware>]> = middleware.into();
+        if let Some(doc) = route.describe() {
+            self.routes.push(doc);
+        }
+        for method in route.methods().clone() {
+            println!("[webf] added {} route {}", method, route.path());
+            let info = Arc::new(RouteInfo {
+                path: route.path().clone(),
+                method: method.clone(),
+                resource: type_name::<R>(),
+                collection: R::get_collection_name(),
+            });
+            let handler = {
+                let ds = self.data_source.clone();
+                let route = route.clone();
+                let global = self.middleware.clone();
+                let local = local.clone();
+                move |req: Request<Body>| {
+                    let ds = ds.clone();
+                    let route = route.clone();
+                    let global = global.clone();
+                    let local = local.clone();
+                    let info = info.clone();
+                    async move {
+                        let method = info.method.clone();
+                        let res = middleware::run(&global, &local, &info, req, |req| async move {
+                            match method {
+                                Method::GET => route.handler_get(ds, req).await,
+                                Method::POST => route.handler_post(ds, req).await,
+                                Method::PUT => route.handler_put(ds, req).await,
+                                Method::PATCH => route.handler_patch(ds, req).await,
+                                Method::DELETE => route.handler_delete(ds, req).await,
+                                _ => Err(Error::MethodNotAllowed),
                             }
-                        }
-                    };
-                    let mut refer = std::mem::take(&mut self.router_builder);
-                    self.router_builder = refer.post(route.path(), handler);
-                }
-                _ => {
-                    return
+                        }).await;
+                        Ok::<_, Infallible>(res)
+                    }
                 }
-            }
+            };
+            let builder = std::mem::take(&mut self.router_builder);
+            self.router_builder = builder.add(route.path(), vec![method], handler);
         }
     }
 }
@@ -0,0 +1,136 @@
+use std::fmt::Display;
+
+use mongodb::bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+// a type that can identify a DataResource
+pub trait ResourceId: Serialize + DeserializeOwned + Display + Clone + Send + Sync + 'static {
+    // parse the `:id` path param or an `after` cursor
+    fn parse(raw: &str) -> Option<Self>;
+
+    // the value as it is stored, for use in filters
+    fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    // id for a new resource when it doesn't pick a generator, None leaves it to the data layer
+    fn generate() -> Option<Self>;
+
+    // integer ids are counted up per collection by a data layer assigning them, other ids are
+    // made up as ObjectIds; also how path params and cursors are documented
+    const INTEGER: bool = false;
+}
+
+macro_rules! integer_id {
+    ($($int:ty),*) => {$(
+        impl ResourceId for $int {
+            const INTEGER: bool = true;
+
+            fn parse(raw: &str) -> Option<Self> {
+                raw.parse().ok()
+            }
+
+            fn generate() -> Option<Self> {
+                None
+            }
+        }
+    )*};
+}
+
+integer_id!(u32, u64, i32, i64);
+
+impl ResourceId for String {
+    fn parse(raw: &str) -> Option<Self> {
+        Some(raw.to_string())
+    }
+
+    fn generate() -> Option<Self> {
+        UlidGen.next_id()
+    }
+}
+
+impl ResourceId for ObjectId {
+    fn parse(raw: &str) -> Option<Self> {
+        ObjectId::parse_str(raw).ok()
+    }
+
+    fn generate() -> Option<Self> {
+        None
+    }
+}
+
+// whatever the data layer stored, for records whose id nobody looks up, e.g. the migration history
+impl ResourceId for Value {
+    fn parse(raw: &str) -> Option<Self> {
+        Some(Value::String(raw.to_string()))
+    }
+
+    fn generate() -> Option<Self> {
+        None
+    }
+}
+
+impl ResourceId for Uuid {
+    fn parse(raw: &str) -> Option<Self> {
+        Uuid::parse_str(raw).ok()
+    }
+
+    fn generate() -> Option<Self> {
+        UuidV4.next_id()
+    }
+}
+
+// assigns ids to new resources, picked with `#[rsweb(id_generator = "...")]`
+pub trait IdGenerator<Id> {
+    // None leaves it to the data layer
+    fn next_id(&self) -> Option<Id>;
+}
+
+// the data layer assigns the id on insert: an ObjectId, or the next number of the collection for integer ids
+pub struct BackendAssigned;
+
+impl<Id> IdGenerator<Id> for BackendAssigned {
+    fn next_id(&self) -> Option<Id> {
+        None
+    }
+}
+
+pub struct UuidV4;
+
+impl IdGenerator<Uuid> for UuidV4 {
+    fn next_id(&self) -> Option<Uuid> {
+        Some(Uuid::new_v4())
+    }
+}
+
+impl IdGenerator<String> for UuidV4 {
+    fn next_id(&self) -> Option<String> {
+        Some(Uuid::new_v4().to_string())
+    }
+}
+
+// time-ordered uuids, so newer resources sort after older ones
+pub struct UuidV7;
+
+impl IdGenerator<Uuid> for UuidV7 {
+    fn next_id(&self) -> Option<Uuid> {
+        Some(Uuid::now_v7())
+    }
+}
+
+impl IdGenerator<String> for UuidV7 {
+    fn next_id(&self) -> Option<String> {
+        Some(Uuid::now_v7().to_string())
+    }
+}
+
+pub struct UlidGen;
+
+impl IdGenerator<String> for UlidGen {
+    fn next_id(&self) -> Option<String> {
+        Some(ulid::Ulid::new().to_string())
+    }
+}
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use hyper::{Body, HeaderMap, Method, Request, Response, Uri};
+use hyper::http::Extensions;
+
+use crate::error::{Error, wants_problem_json};
+
+// the route a request was resolved to
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteInfo {
+    // the route's pattern, e.g. `/movies/:id`
+    pub path: String,
+    pub method: Method,
+    // the resource type's name and its collection
+    pub resource: String,
+    pub collection: String,
+}
+
+// the request as it came in, for the after hooks
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub started: Instant,
+    pub route: RouteInfo,
+    pub locals: Locals,
+}
+
+// values a middleware hands from its before hook to its after hook, the before hooks find it
+// in the request's extensions
+#[derive(Debug, Clone, Default)]
+pub struct Locals(Arc<Mutex<Extensions>>);
+
+impl Locals {
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(value);
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get::<T>().cloned()
+    }
+}
+
+pub enum Flow {
+    // hand the (possibly changed) request on to the next middleware and finally the handler
+    Continue(Request<Body>),
+    // answer right away, the handler and the remaining middleware are skipped
+    Respond(Response<Body>),
+}
+
+// cross-cutting behavior around the route handlers, e.g. logging, auth, CORS or compression;
+// the before hooks run in order, global middleware first, and the after hooks in reverse
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    // an error short-circuits like Flow::Respond, rendered as the handler's errors are
+    async fn before(&self, req: Request<Body>, _route: &RouteInfo) -> Result<Flow, Error> {
+        Ok(Flow::Continue(req))
+    }
+
+    // runs for every middleware whose before hook continued, on error responses as well
+    async fn after(&self, res: Response<Body>, _req: &RequestInfo) -> Response<Body> {
+        res
+    }
+}
+
+// global middleware is shared with the handlers, so it also wraps routes added before it
+pub(crate) type Stack = Arc<RwLock<Vec<Arc<dyn Middleware>>>>;
+
+pub(crate) async fn run<H, F>(global: &Stack, local: &[Arc<dyn Middleware>], route: &RouteInfo, mut req: Request<Body>, handler: H) -> Response<Body>
+    where H: FnOnce(Request<Body>) -> F, F: Future<Output = Result<Response<Body>, Error>>
+{
+    let mut chain: Vec<Arc<dyn Middleware>> = global.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    chain.extend(local.iter().cloned());
+
+    let problem = wants_problem_json(&req);
+    let info = RequestInfo {
+        method: req.method().clone(),
+        uri: req.uri().clone(),
+        headers: req.headers().clone(),
+        started: Instant::now(),
+        route: route.clone(),
+        locals: Locals::default(),
+    };
+    req.extensions_mut().insert(info.locals.clone());
+
+    let mut req = Some(req);
+    let mut ran = 0;
+    let mut early = None;
+    for middleware in &chain {
+        match middleware.before(req.take().expect("Unreachable"), route).await {
+            Ok(Flow::Continue(next)) => {
+                req = Some(next);
+                ran += 1;
+            }
+            Ok(Flow::Respond(res)) => {
+                early = Some(res);
+                break;
+            }
+            Err(err) => {
+                early = Some(err.into_response(problem));
+                break;
+            }
+        }
+    }
+    let mut res = match (early, req) {
+        (Some(res), _) => res,
+        (None, Some(req)) => handler(req).await.unwrap_or_else(|err| err.into_response(problem)),
+        (None, None) => unreachable!(),
+    };
+    for middleware in chain[..ran].iter().rev() {
+        res = middleware.after(res, &info).await;
+    }
+    res
+}
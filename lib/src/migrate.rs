@@ -0,0 +1,249 @@
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use rsweb_macros::{DataResource, Fields};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::application::{Database, Field, Fields, FieldType, RetrieveOptions, SortOrder};
+use crate::error::Error;
+use crate::frontend_http::{Application, DataResource};
+
+// where applied steps are recorded, it is migrated like any other collection
+pub const MIGRATIONS_COLLECTION: &str = "_rsweb_migrations";
+
+// declared shape of a collection, as the Fields derive describes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub collection: String,
+    pub fields: Vec<Field>,
+}
+
+impl Schema {
+    pub fn of<R: DataResource + Fields>() -> Self {
+        Schema { collection: R::get_collection_name(), fields: R::fields() }
+    }
+
+    // top-level fields that want an index, the id is always indexed by the backend
+    pub fn indexed(&self) -> impl Iterator<Item = &Field> {
+        self.fields.iter().filter(|field| field.indexed && field.name != "_id")
+    }
+
+    // index steps for a collection created from scratch
+    fn index_steps(&self) -> Vec<MigrationStep> {
+        self.indexed()
+            .map(|field| MigrationStep::CreateIndex { collection: self.collection.clone(), field: field.name.clone(), unique: field.unique })
+            .collect()
+    }
+
+    // create the collection along with its indexes
+    pub(crate) fn create_steps(&self) -> Vec<MigrationStep> {
+        let mut steps = vec![MigrationStep::CreateCollection(self.clone())];
+        steps.extend(self.index_steps());
+        steps
+    }
+
+    // the indexes whose names aren't among `existing`
+    pub(crate) fn missing_indexes(&self, existing: &[String]) -> Vec<MigrationStep> {
+        self.index_steps()
+            .into_iter()
+            .filter(|step| match step {
+                MigrationStep::CreateIndex { collection, field, .. } => !existing.contains(&index_name(collection, field)),
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationStep {
+    // a table with a column per field, or a mongo collection with its validator
+    CreateCollection(Schema),
+    // a column the table doesn't have yet, added as nullable since existing rows have no value for it
+    AddField { collection: String, field: Field },
+    CreateIndex { collection: String, field: String, unique: bool },
+    // mongo only, replaces the collection's `$jsonSchema` validator
+    SetValidator { collection: String, validator: Value },
+}
+
+impl MigrationStep {
+    pub fn collection(&self) -> &str {
+        match self {
+            MigrationStep::CreateCollection(schema) => &schema.collection,
+            MigrationStep::AddField { collection, .. }
+            | MigrationStep::CreateIndex { collection, .. }
+            | MigrationStep::SetValidator { collection, .. } => collection,
+        }
+    }
+}
+
+impl Display for MigrationStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationStep::CreateCollection(schema) => write!(f, "create collection `{}`", schema.collection),
+            MigrationStep::AddField { collection, field } => write!(f, "add field `{}.{}`", collection, field.name),
+            MigrationStep::CreateIndex { collection, field, unique: true } => write!(f, "create unique index on `{}.{}`", collection, field),
+            MigrationStep::CreateIndex { collection, field, unique: false } => write!(f, "create index on `{}.{}`", collection, field),
+            MigrationStep::SetValidator { collection, .. } => write!(f, "set validator of `{}`", collection),
+        }
+    }
+}
+
+// indexes are named after what they cover, that's how an existing one is recognized
+pub fn index_name(collection: &str, field: &str) -> String {
+    format!("idx_{}_{}", collection, field.replace('.', "_"))
+}
+
+// `$jsonSchema` for mongo's validator, fields without descriptors such as ids and maps aren't constrained
+pub fn json_schema(fields: &[Field]) -> Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = vec![];
+    for field in fields {
+        let mut schema = type_schema(&field.field_type);
+        if field.nullable {
+            if let Some(Value::Array(types)) = schema.get_mut("bsonType") {
+                types.push(json!("null"));
+            }
+        }
+        properties.insert(field.name.clone(), schema);
+        // mongo fills in a missing id itself
+        if !field.optional && field.name != "_id" {
+            required.push(json!(field.name));
+        }
+    }
+    let mut schema = json!({ "bsonType": ["object"], "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = Value::Array(required);
+    }
+    schema
+}
+
+fn type_schema(field_type: &FieldType) -> Value {
+    match field_type {
+        FieldType::Int => json!({ "bsonType": ["int", "long"] }),
+        FieldType::Float => json!({ "bsonType": ["double", "int", "long"] }),
+        FieldType::Bool => json!({ "bsonType": ["bool"] }),
+        FieldType::String => json!({ "bsonType": ["string"] }),
+        FieldType::Array(inner) => json!({ "bsonType": ["array"], "items": type_schema(inner) }),
+        FieldType::Object(Some(fields)) => json_schema(fields),
+        FieldType::Object(None) => json!({}),
+    }
+}
+
+// a data layer that can bring its storage in line with the declared schemas
+#[async_trait]
+pub trait Migrate: Database {
+    // diff the schemas against the live backend, nothing is changed yet
+    async fn plan(&self, schemas: &[Schema]) -> Result<Vec<MigrationStep>, Self::Error>;
+    async fn apply(&self, step: &MigrationStep) -> Result<(), Self::Error>;
+
+    // a collection the Migrator reads and writes itself, for backends that have to know the shape
+    // of a collection before they can use it
+    fn register(&self, _schema: &Schema) {}
+}
+
+// bookkeeping entry for an applied step
+#[derive(Debug, Clone, Serialize, Deserialize, DataResource, Fields)]
+#[rsweb(collection = "_rsweb_migrations")]
+pub struct AppliedMigration {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub step: String,
+    // seconds since the unix epoch
+    pub applied_at: i64,
+}
+
+// the resources whose collections are managed, run it at startup or through `run_command`
+#[derive(Debug, Clone, Default)]
+pub struct Migrator {
+    schemas: Vec<Schema>,
+}
+
+impl Migrator {
+    pub fn new() -> Self {
+        Migrator::default()
+    }
+
+    pub fn resource<R: DataResource + Fields>(mut self) -> Self {
+        self.schemas.push(Schema::of::<R>());
+        self
+    }
+
+    // the declared schemas, bookkeeping first so steps can be recorded as they are applied
+    pub fn schemas(&self) -> Vec<Schema> {
+        let mut schemas = vec![Schema::of::<AppliedMigration>()];
+        schemas.extend(self.schemas.iter().cloned());
+        schemas
+    }
+
+    pub async fn plan<DB: Migrate + Sync>(&self, db: &DB) -> Result<Vec<MigrationStep>, DB::Error> {
+        db.register(&Schema::of::<AppliedMigration>());
+        db.plan(&self.schemas()).await
+    }
+
+    // apply whatever the backend is missing, returns the applied steps
+    pub async fn run<DB: Migrate + Sync>(&self, db: &DB) -> Result<Vec<MigrationStep>, DB::Error> {
+        let steps = self.plan(db).await?;
+        for step in &steps {
+            db.apply(step).await?;
+            let record = AppliedMigration {
+                id: AppliedMigration::next_id(),
+                step: step.to_string(),
+                applied_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0),
+            };
+            db.insert_one::<_, Value>(MIGRATIONS_COLLECTION.to_string(), record).await?;
+        }
+        Ok(steps)
+    }
+
+    // applied steps, oldest first
+    pub async fn history<DB: Migrate + Sync>(&self, db: &DB) -> Result<Vec<AppliedMigration>, DB::Error> {
+        let options = RetrieveOptions {
+            sort: vec![("applied_at".to_string(), SortOrder::Ascending), ("_id".to_string(), SortOrder::Ascending)],
+            ..Default::default()
+        };
+        db.register(&Schema::of::<AppliedMigration>());
+        db.retrieve_many(MIGRATIONS_COLLECTION.to_string(), DB::Filter::default(), options).await
+    }
+
+    // handles `<binary> migrate [run|plan|status]` given the process arguments,
+    // None when they aren't a migrate command and the app should start as usual
+    pub async fn run_command<DB, I>(&self, db: &DB, args: I) -> Option<Result<(), Error>>
+        where DB: Migrate + Sync, I: IntoIterator<Item = String>
+    {
+        let mut args = args.into_iter().skip(1);
+        if args.next().as_deref() != Some("migrate") {
+            return None;
+        }
+        let result = match args.next().as_deref() {
+            None | Some("run") => self.run(db).await.map(|steps| {
+                for step in &steps {
+                    println!("applied: {}", step);
+                }
+                println!("{} migration step(s) applied", steps.len());
+            }),
+            Some("plan") => self.plan(db).await.map(|steps| {
+                for step in &steps {
+                    println!("pending: {}", step);
+                }
+                println!("{} migration step(s) pending", steps.len());
+            }),
+            Some("status") => self.history(db).await.map(|applied| {
+                for migration in &applied {
+                    println!("{} {}", migration.applied_at, migration.step);
+                }
+                println!("{} migration step(s) applied so far", applied.len());
+            }),
+            Some(other) => return Some(Err(Error::bad_request(format!("unknown migrate command `{}`, expected run, plan or status", other)))),
+        };
+        Some(result.map_err(Into::into))
+    }
+}
+
+impl<T> Application<T> where T: Migrate + 'static + Send + Sync {
+    // bring the data layer in line with the migrator's resources before serving
+    pub async fn migrate(&self, migrator: &Migrator) -> Result<Vec<MigrationStep>, T::Error> {
+        migrator.run(&*self.data_source).await
+    }
+}
@@ -0,0 +1,540 @@
+use std::convert::Infallible;
+use std::path::PathBuf;
+
+use hyper::{Body, Method, Request, Response};
+use serde_json::{Map, Value};
+
+use crate::application::{Database, Field, Fields, FieldType};
+use crate::frontend_http::{Application, DataResource};
+use crate::ids::ResourceId;
+
+const OPENAPI_VERSION: &str = "3.1.0";
+// filter operators accepted as `field[op]`, see Op::from_param
+const FILTER_OPS: [&str; 11] = ["eq", "ne", "gt", "gte", "lt", "lte", "in", "nin", "exists", "regex", "contains"];
+
+// which handlers a route runs, that decides the operations it documents
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteKind {
+    Single,
+    Collection,
+    // a collection under the parent whose id is in `parent_param`
+    Nested { parent_param: String, integer_parent: bool },
+}
+
+// what a route tells the OpenAPI document about itself, collected by Application::add_route
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteDoc {
+    pub path: String,
+    pub methods: Vec<Method>,
+    pub kind: RouteKind,
+    // schema name, the resource type's name
+    pub resource: String,
+    pub fields: Vec<Field>,
+    // whether the resource's ids are integers, for the `:id` param, cursors and created ids
+    pub integer_id: bool,
+    // names `?expand=` accepts
+    pub relations: Vec<String>,
+}
+
+impl RouteDoc {
+    pub fn of<R: DataResource + Fields>(path: &str, methods: &[Method], kind: RouteKind, relations: Vec<String>) -> Self {
+        RouteDoc {
+            path: path.to_string(),
+            methods: methods.to_vec(),
+            kind,
+            resource: type_name::<R>(),
+            fields: R::fields(),
+            integer_id: R::Id::INTEGER,
+            relations,
+        }
+    }
+}
+
+// `my_app::models::Movie` is documented as `Movie`
+pub(crate) fn type_name<R>() -> String {
+    let full = std::any::type_name::<R>();
+    let base = full.split('<').next().unwrap_or(full);
+    base.rsplit("::").next().unwrap_or(base).to_string()
+}
+
+// an HTML page rendering the document, its scripts come from pinned releases on jsDelivr
+// unless the config points `docs_assets` at a local copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocsPage {
+    SwaggerUi,
+    Redoc,
+}
+
+// exact versions so the page doesn't change under the application, bump them deliberately
+const SWAGGER_UI_CDN: &str = "https://cdn.jsdelivr.net/npm/swagger-ui-dist@5.17.14";
+const REDOC_CDN: &str = "https://cdn.jsdelivr.net/npm/redoc@2.1.5/bundles";
+
+impl DocsPage {
+    // the files the page loads, by name and content type
+    fn assets(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            DocsPage::SwaggerUi => &[("swagger-ui.css", "text/css"), ("swagger-ui-bundle.js", "text/javascript")],
+            DocsPage::Redoc => &[("redoc.standalone.js", "text/javascript")],
+        }
+    }
+
+    fn cdn(self) -> &'static str {
+        match self {
+            DocsPage::SwaggerUi => SWAGGER_UI_CDN,
+            DocsPage::Redoc => REDOC_CDN,
+        }
+    }
+}
+
+pub struct OpenApiConfig {
+    pub title: String,
+    pub version: String,
+    // where the document is served
+    pub path: String,
+    pub docs: Option<DocsPage>,
+    // where the docs page is served, if there is one
+    pub docs_path: String,
+    // a directory holding the page's files, e.g. the `swagger-ui-dist` package or redoc's `bundles`,
+    // served next to the page so it works offline and under a strict CSP
+    pub docs_assets: Option<PathBuf>,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        OpenApiConfig {
+            title: "API".to_string(),
+            version: "0.1.0".to_string(),
+            path: "/openapi.json".to_string(),
+            docs: None,
+            docs_path: "/docs".to_string(),
+            docs_assets: None,
+        }
+    }
+}
+
+impl OpenApiConfig {
+    pub fn new<T: Into<String>, V: Into<String>>(title: T, version: V) -> Self {
+        OpenApiConfig { title: title.into(), version: version.into(), ..Default::default() }
+    }
+
+    pub fn with_docs(mut self, docs: DocsPage) -> Self {
+        self.docs = Some(docs);
+        self
+    }
+
+    pub fn with_docs_assets<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.docs_assets = Some(dir.into());
+        self
+    }
+}
+
+// the OpenAPI 3.1 document describing `routes`
+pub fn document(config: &OpenApiConfig, routes: &[RouteDoc]) -> Value {
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    schemas.insert("Error".to_string(), error_schema());
+    schemas.insert("Problem".to_string(), problem_schema());
+    for route in routes {
+        schemas.insert(route.resource.clone(), resource_schema(&route.fields, false));
+        schemas.insert(format!("{}Patch", route.resource), resource_schema(&route.fields, true));
+
+        let item = paths.entry(openapi_path(&route.path)).or_insert_with(|| json!({}));
+        for method in &route.methods {
+            if let Some(operation) = operation(route, method) {
+                item[method.as_str().to_lowercase()] = operation;
+            }
+        }
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": { "title": config.title, "version": config.version },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "responses": {
+                "BadRequest": error_response("malformed input or query params"),
+                "Unauthorized": error_response("authentication required"),
+                "Forbidden": error_response("access denied"),
+                "NotFound": error_response("resource not found"),
+                "Unprocessable": error_response("the body broke validation rules"),
+            },
+        },
+    })
+}
+
+// routerify's `/users/:id` is `/users/{id}`
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(param) => format!("{{{}}}", param),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn path_params(route: &RouteDoc) -> Vec<Value> {
+    route.path.split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .map(|param| {
+            let integer = match &route.kind {
+                RouteKind::Single => param == "id" && route.integer_id,
+                RouteKind::Nested { parent_param, integer_parent } => param == parent_param && *integer_parent,
+                RouteKind::Collection => false,
+            };
+            json!({ "name": param, "in": "path", "required": true, "schema": id_schema(integer) })
+        })
+        .collect()
+}
+
+fn id_schema(integer: bool) -> Value {
+    if integer {
+        json!({ "type": "integer" })
+    } else {
+        json!({ "type": "string" })
+    }
+}
+
+// None for methods the route answers with 405
+fn operation(route: &RouteDoc, method: &Method) -> Option<Value> {
+    let reference = json!({ "$ref": format!("#/components/schemas/{}", route.resource) });
+    let nested = matches!(route.kind, RouteKind::Nested { .. });
+    let mut parameters = path_params(route);
+    let (summary, mut responses, body) = match (&route.kind, method) {
+        (RouteKind::Single, &Method::GET) => {
+            parameters.extend(expand_param(&route.relations));
+            ("get", responses(&[("200", json_content("the resource", &reference))], &["400", "403", "404"]), None)
+        }
+        (RouteKind::Single, &Method::PUT) => {
+            ("replace", responses(&[("200", json_content("the saved resource", &reference))], &["400", "403", "404", "422"]), Some(reference.clone()))
+        }
+        (RouteKind::Single, &Method::PATCH) => {
+            let patch = json!({ "$ref": format!("#/components/schemas/{}Patch", route.resource) });
+            ("update", responses(&[("200", json_content("the saved resource", &reference))], &["400", "403", "404", "422"]), Some(patch))
+        }
+        (RouteKind::Single, &Method::DELETE) => {
+            ("delete", responses(&[("204", json!({ "description": "deleted" }))], &["400", "403", "404"]), None)
+        }
+        (RouteKind::Collection, &Method::GET) | (RouteKind::Nested { .. }, &Method::GET) => {
+            parameters.extend(page_params(&route.fields, route.integer_id));
+            parameters.extend(expand_param(&route.relations));
+            parameters.extend(filter_params(&route.fields));
+            let page = json!({
+                "type": "object",
+                "properties": {
+                    "data": { "type": "array", "items": reference },
+                    "next": { "type": ["string", "null"], "description": "link to the following page" },
+                    "total": { "type": "integer", "description": "only with `count=true`" },
+                },
+                "required": ["data", "next"],
+            });
+            ("list", responses(&[("200", json_content("a page of resources", &page))], &["400", "403"]), None)
+        }
+        (RouteKind::Collection, &Method::POST) | (RouteKind::Nested { .. }, &Method::POST) => {
+            parameters.push(json!({
+                "name": "ordered",
+                "in": "query",
+                "description": "for an array body, `false` keeps going past failed items",
+                "schema": { "type": "boolean", "default": true },
+            }));
+            let created = json!({ "type": "object", "properties": { "id": id_schema(route.integer_id) }, "required": ["id"] });
+            let results = json!({
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": id_schema(route.integer_id),
+                        "error": { "type": "string" },
+                        "fields": { "type": "object", "additionalProperties": { "type": "string" } },
+                    },
+                },
+            });
+            let body = json!({ "oneOf": [reference, { "type": "array", "items": reference }] });
+            let responses = responses(
+                &[("201", json_content("created from a single resource", &created)), ("200", json_content("one result per item of an array body", &results))],
+                &["400", "403", "422"],
+            );
+            ("create", responses, Some(body))
+        }
+        _ => return None,
+    };
+    if nested {
+        // the parent has to exist and be viewable
+        responses["404"] = json!({ "$ref": "#/components/responses/NotFound" });
+    }
+
+    let mut operation = json!({
+        "operationId": operation_id(method, &route.path),
+        "summary": format!("{} {}", summary, route.resource),
+        "tags": [route.resource],
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        operation["parameters"] = Value::Array(parameters);
+    }
+    if let Some(body) = body {
+        operation["requestBody"] = json!({ "required": true, "content": { "application/json": { "schema": body } } });
+    }
+    Some(operation)
+}
+
+// `get_users_id_movies` for `GET /users/:id/movies`
+fn operation_id(method: &Method, path: &str) -> String {
+    let mut id = method.as_str().to_lowercase();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        id.push('_');
+        id.push_str(segment.trim_start_matches(':'));
+    }
+    id
+}
+
+fn json_content(description: &str, schema: &Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn responses(success: &[(&str, Value)], errors: &[&str]) -> Value {
+    let mut responses = Map::new();
+    for (status, response) in success {
+        responses.insert(status.to_string(), response.clone());
+    }
+    for status in errors {
+        let name = match *status {
+            "400" => "BadRequest",
+            "401" => "Unauthorized",
+            "403" => "Forbidden",
+            "404" => "NotFound",
+            _ => "Unprocessable",
+        };
+        responses.insert(status.to_string(), json!({ "$ref": format!("#/components/responses/{}", name) }));
+    }
+    Value::Object(responses)
+}
+
+fn expand_param(relations: &[String]) -> Option<Value> {
+    if relations.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "name": "expand",
+        "in": "query",
+        "description": format!("comma separated relations to embed, of: {}", relations.join(", ")),
+        "schema": { "type": "string" },
+    }))
+}
+
+// `limit`, `offset`, `after`, `sort`, `fields` and `count`, see CollectionRoute::handler_get
+fn page_params(fields: &[Field], integer_id: bool) -> Vec<Value> {
+    let sortable: Vec<&str> = fields.iter().filter(|field| field.sortable && !field.is_private()).map(|field| field.name.as_str()).collect();
+    vec![
+        json!({ "name": "limit", "in": "query", "schema": { "type": "integer", "minimum": 1, "maximum": 1000, "default": 50 } }),
+        json!({ "name": "offset", "in": "query", "schema": { "type": "integer", "minimum": 0, "default": 0 } }),
+        json!({ "name": "after", "in": "query", "description": "id to continue after, can't be combined with `offset` or `sort`", "schema": id_schema(integer_id) }),
+        json!({
+            "name": "sort",
+            "in": "query",
+            "description": format!("comma separated keys, `-` in front for descending, of: {}", sortable.join(", ")),
+            "schema": { "type": "string" },
+        }),
+        json!({ "name": "fields", "in": "query", "description": "comma separated fields to include", "schema": { "type": "string" } }),
+        json!({ "name": "count", "in": "query", "description": "include the `total` of matching resources", "schema": { "type": "boolean" } }),
+    ]
+}
+
+// an equality match per filterable top-level field, the operators don't fit OpenAPI's parameter names
+fn filter_params(fields: &[Field]) -> Vec<Value> {
+    fields
+        .iter()
+        .filter(|field| field.filterable && !field.is_private())
+        .filter_map(|field| {
+            let schema = match &field.field_type {
+                FieldType::Array(inner) => type_schema(inner),
+                FieldType::Object(_) => return None,
+                field_type => type_schema(field_type),
+            };
+            Some(json!({
+                "name": field.name,
+                "in": "query",
+                "description": format!("equality match, `{}[op]` picks one of: {}", field.name, FILTER_OPS.join(", ")),
+                "schema": schema,
+            }))
+        })
+        .collect()
+}
+
+// hidden fields are left out, read-only ones are only sent and write-only ones only accepted;
+// a patch schema has nothing required
+pub fn resource_schema(fields: &[Field], patch: bool) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    for field in fields.iter().filter(|field| !field.hidden) {
+        let mut schema = type_schema(&field.field_type);
+        if field.nullable {
+            if let Some(Value::String(name)) = schema.get("type").cloned() {
+                schema["type"] = json!([name, "null"]);
+            }
+        }
+        if field.readonly {
+            schema["readOnly"] = json!(true);
+        }
+        if field.write_only {
+            schema["writeOnly"] = json!(true);
+        }
+        properties.insert(field.name.clone(), schema);
+        if !field.optional && !patch {
+            required.push(json!(field.name));
+        }
+    }
+    let mut schema = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = Value::Array(required);
+    }
+    schema
+}
+
+fn type_schema(field_type: &FieldType) -> Value {
+    match field_type {
+        FieldType::Int => json!({ "type": "integer" }),
+        FieldType::Float => json!({ "type": "number" }),
+        FieldType::Bool => json!({ "type": "boolean" }),
+        FieldType::String => json!({ "type": "string" }),
+        FieldType::Array(inner) => json!({ "type": "array", "items": type_schema(inner) }),
+        FieldType::Object(Some(fields)) => resource_schema(fields, false),
+        // ids and maps have no descriptors
+        FieldType::Object(None) => json!({}),
+    }
+}
+
+// the `{"error": ...}` body of Error::into_response
+fn error_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "error": {
+                "type": "object",
+                "properties": {
+                    "status": { "type": "integer" },
+                    "code": { "type": "string" },
+                    "message": { "type": "string" },
+                    "fields": { "type": "object", "additionalProperties": { "type": "string" } },
+                },
+                "required": ["status", "code", "message"],
+            },
+        },
+        "required": ["error"],
+    })
+}
+
+// sent instead when the request accepts application/problem+json
+fn problem_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "type": { "type": "string" },
+            "title": { "type": "string" },
+            "status": { "type": "integer" },
+            "detail": { "type": "string" },
+            "errors": { "type": "object", "additionalProperties": { "type": "string" } },
+        },
+        "required": ["type", "title", "status", "detail"],
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+            "application/problem+json": { "schema": { "$ref": "#/components/schemas/Problem" } },
+        },
+    })
+}
+
+// `assets` is the URL the page's files are found under
+fn docs_html(page: DocsPage, title: &str, spec_path: &str, assets: &str) -> String {
+    let title = html_escape(title);
+    let assets = html_escape(assets.trim_end_matches('/'));
+    match page {
+        DocsPage::SwaggerUi => format!(
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<link rel="stylesheet" href="{assets}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="{assets}/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({{ url: {spec}, dom_id: "#swagger-ui" }});</script>
+</body>
+</html>
+"##,
+            title = title,
+            assets = assets,
+            spec = Value::String(spec_path.to_string()),
+        ),
+        DocsPage::Redoc => format!(
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+</head>
+<body>
+<redoc spec-url="{spec}"></redoc>
+<script src="{assets}/redoc.standalone.js"></script>
+</body>
+</html>
+"##,
+            title = title,
+            assets = assets,
+            spec = html_escape(spec_path),
+        ),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl<T> Application<T> where T: Database + 'static + Send + Sync {
+    // the document for the routes added so far
+    pub fn openapi(&self, config: &OpenApiConfig) -> Value {
+        document(config, &self.routes)
+    }
+
+    // serve the document, and the docs page if configured; add it after the routes it should describe.
+    // Fails if `docs_assets` lacks a file the page needs
+    pub fn add_openapi(&mut self, config: OpenApiConfig) -> std::io::Result<()> {
+        let spec = self.openapi(&config).to_string();
+        let mut pages = vec![(config.path.clone(), "application/json", spec.into_bytes())];
+        if let Some(docs) = config.docs {
+            let assets = match &config.docs_assets {
+                Some(dir) => {
+                    for (name, content_type) in docs.assets() {
+                        let path = format!("{}/{}", config.docs_path.trim_end_matches('/'), name);
+                        pages.push((path, content_type, std::fs::read(dir.join(name))?));
+                    }
+                    config.docs_path.clone()
+                }
+                None => docs.cdn().to_string(),
+            };
+            pages.push((config.docs_path.clone(), "text/html; charset=utf-8", docs_html(docs, &config.title, &config.path, &assets).into_bytes()));
+        }
+        for (path, content_type, body) in pages {
+            println!("[webf] added GET route {}", path);
+            let handler = move |_: Request<Body>| {
+                let body = body.clone();
+                async move {
+                    Ok::<_, Infallible>(Response::builder()
+                        .header(hyper::header::CONTENT_TYPE, content_type)
+                        .body(Body::from(body))
+                        .unwrap())
+                }
+            };
+            let builder = std::mem::take(&mut self.router_builder);
+            self.router_builder = builder.get(path, handler);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use serde_json::Value;
+
+use crate::application::{Filter, matches, Query};
+use crate::error::Error;
+use crate::frontend_http::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // a single item, also when it is embedded through `expand`
+    Read,
+    List,
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    pub const ALL: [Action; 5] = [Action::Read, Action::List, Action::Create, Action::Update, Action::Delete];
+}
+
+// who a rule applies to, as told by Context::roles and Context::principal_id
+#[derive(Debug, Clone, PartialEq)]
+pub enum Grantee {
+    Anyone,
+    // callers with a principal id
+    Authenticated,
+    Role(String),
+}
+
+impl Grantee {
+    pub fn role<S: Into<String>>(name: S) -> Self {
+        Grantee::Role(name.into())
+    }
+
+    fn includes<C: Context>(&self, ctx: &C) -> bool {
+        match self {
+            Grantee::Anyone => true,
+            Grantee::Authenticated => ctx.principal_id().is_some(),
+            Grantee::Role(name) => ctx.roles().iter().any(|role| role == name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Always,
+    // the field holds the caller's principal id
+    Owner(String),
+    Equals(String, Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    grantee: Grantee,
+    actions: Vec<Action>,
+    condition: Condition,
+}
+
+// who may do what with a resource, see Protected::policy; nothing is allowed unless a rule
+// grants it and the rules add up, e.g.
+// `Policy::new().allow(Grantee::role("admin"), &Action::ALL).allow_owner("user_id", &[Action::Update])`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(self, grantee: Grantee, actions: &[Action]) -> Self {
+        self.rule(grantee, actions, Condition::Always)
+    }
+
+    // only on the items whose `field` is the caller's principal id
+    pub fn allow_owner<F: Into<String>>(self, field: F, actions: &[Action]) -> Self {
+        self.rule(Grantee::Authenticated, actions, Condition::Owner(field.into()))
+    }
+
+    // only on the items whose `field` is `value`, e.g. the published ones
+    pub fn allow_where<F: Into<String>, V: Into<Value>>(self, grantee: Grantee, field: F, value: V, actions: &[Action]) -> Self {
+        self.rule(grantee, actions, Condition::Equals(field.into(), value.into()))
+    }
+
+    fn rule(mut self, grantee: Grantee, actions: &[Action], condition: Condition) -> Self {
+        self.rules.push(Rule { grantee, actions: actions.to_vec(), condition });
+        self
+    }
+
+    // what the rules granting `action` to the caller add up to
+    pub fn access<C: Context>(&self, ctx: &C, action: Action) -> Access {
+        let mut matching = vec![];
+        for rule in self.rules.iter().filter(|rule| rule.actions.contains(&action) && rule.grantee.includes(ctx)) {
+            match &rule.condition {
+                Condition::Always => return Access::All,
+                Condition::Owner(field) => {
+                    if let Some(id) = ctx.principal_id() {
+                        matching.push((field.clone(), id));
+                    }
+                }
+                Condition::Equals(field, value) => matching.push((field.clone(), value.clone())),
+            }
+        }
+        if matching.is_empty() {
+            Access::Denied
+        } else {
+            Access::Matching(matching)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    All,
+    // the items where any of the fields has its value
+    Matching(Vec<(String, Value)>),
+    Denied,
+}
+
+impl Access {
+    // `item` is the resource's JSON form, fields are resolved the way `restrict` queries them,
+    // so dotted paths reach into nested objects
+    pub fn permits(&self, item: &Value) -> bool {
+        match self {
+            Access::All => true,
+            Access::Matching(matching) => matching.iter().any(|(field, value)| {
+                matches(item, &Query::field(field.as_str()).eq(value.clone())).unwrap_or(false)
+            }),
+            Access::Denied => false,
+        }
+    }
+
+    pub fn check(&self, item: &Value) -> Result<(), Error> {
+        if self.permits(item) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+
+    // push the restriction down into a listing's filter instead of dropping items afterwards,
+    // so pages and counts only ever see what the caller may
+    pub fn restrict<F: Filter>(&self, filter: &mut F) -> Result<(), Error> {
+        match self {
+            Access::All => {}
+            Access::Matching(matching) => {
+                let mut queries: Vec<Query> = matching.iter().map(|(field, value)| Query::field(field.as_str()).eq(value.clone())).collect();
+                filter.add(if queries.len() == 1 { queries.remove(0) } else { Query::or(queries) });
+            }
+            Access::Denied => return Err(Error::Forbidden),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use serde_json::Value;
+
+use crate::application::{Database, Fields, Filter, Query, RetrieveOptions, strip_private};
+use crate::error::{Error, to_json};
+use crate::frontend_http::{Context, DataResource, Protected};
+use crate::policy::{Access, Action};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelationKind {
+    // this resource holds the key of a single related one, e.g. a movie's `user_id`
+    BelongsTo,
+    // related resources hold this one's id, e.g. the comments of a movie
+    HasMany,
+}
+
+// a relation `?expand=` can embed, matching `local_key` of this resource against `foreign_key` of the related one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relation {
+    pub name: String,
+    pub kind: RelationKind,
+    pub local_key: String,
+    pub foreign_key: String,
+}
+
+// the key values to look up
+pub type Keys = Vec<Value>;
+
+// related items as `(foreign key, rendered item)` pairs
+pub type RelatedFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<(Value, Value)>, Error>> + Send + 'a>>;
+
+// the relations of a resource, implemented by the DataResource derive from its
+// `#[rsweb(belongs_to = "...")]` fields and `#[rsweb(has_many(...))]` attributes;
+// only routes wrapped in frontend_http::Expanding need it
+pub trait Related<S: Context + Send + Sync> {
+    fn relations() -> Vec<Relation> {
+        vec![]
+    }
+
+    // the related items of the relation called `name` whose foreign key is among `keys`, as `ctx` may see them
+    fn load_related<'a, DB: Database + Send + Sync>(name: &'a str, _data_layer: &'a DB, _keys: Keys, _ctx: &'a S) -> RelatedFuture<'a> {
+        Box::pin(async move { Err(unknown_relation(name)) })
+    }
+}
+
+// what routes that don't expand relations use in place of the resource, every name is unknown to it
+pub(crate) struct NoRelations;
+
+impl<S: Context + Send + Sync> Related<S> for NoRelations {}
+
+#[doc(hidden)]
+pub fn unknown_relation(name: &str) -> Error {
+    Error::invalid("expand", format!("unknown relation `{}`", name))
+}
+
+// one query for all the keys, restricted by T's read policy, each item goes through T's filter_view_data
+pub async fn load<T, S, DB>(data_layer: &DB, foreign_key: &str, keys: Keys, ctx: &S) -> Result<Vec<(Value, Value)>, Error>
+    where T: DataResource + Fields + Protected<S> + Send + Sync, S: Context + Send + Sync, DB: Database + Send + Sync
+{
+    let mut filter = DB::Filter::default();
+    filter.add(Query::field(foreign_key).is_in(keys));
+    // items the caller can't read are left out, as if they didn't exist
+    let access = T::policy().access(ctx, Action::Read);
+    if access == Access::Denied {
+        return Ok(vec![]);
+    }
+    access.restrict(&mut filter)?;
+    let items: Vec<T> = data_layer.retrieve_many(T::get_collection_name(), filter, RetrieveOptions::default()).await.map_err(Into::into)?;
+    let fields = T::fields();
+    let mut loaded = vec![];
+    for item in items {
+        let mut map: HashMap<String, Value> = serde_json::from_value(to_json(&item)?).map_err(Error::backend)?;
+        // taken before the view hook, which may hide the key
+        let key = map.get(foreign_key).cloned().unwrap_or(Value::Null);
+        item.filter_view_data(ctx, &mut map);
+        strip_private(&fields, &mut map);
+        loaded.push((key, to_json(&map)?));
+    }
+    Ok(loaded)
+}
+
+// embed the relations named in the comma separated `expand` param into the rendered items,
+// a belongs_to relation becomes an object or null, a has_many one an array
+pub(crate) async fn expand<R, S, DB>(expand: Option<&String>, views: &mut [HashMap<String, Value>], data_layer: &DB, ctx: &S) -> Result<(), Error>
+    where R: Related<S>, S: Context + Send + Sync, DB: Database + Send + Sync
+{
+    let names = match expand {
+        Some(names) => names.split(',').filter(|name| !name.is_empty()),
+        None => return Ok(()),
+    };
+    let relations = R::relations();
+    for name in names {
+        let relation = relations.iter().find(|relation| relation.name == name).ok_or_else(|| unknown_relation(name))?;
+
+        // the keys are only known from the rendered items, a view that hides one gets nothing embedded for it
+        let mut keys: Vec<Value> = vec![];
+        for key in views.iter().filter_map(|view| view.get(&relation.local_key)) {
+            if !key.is_null() && !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+        let mut related: HashMap<String, Vec<Value>> = HashMap::new();
+        if !keys.is_empty() {
+            for (key, item) in R::load_related(name, data_layer, keys, ctx).await? {
+                related.entry(key.to_string()).or_default().push(item);
+            }
+        }
+
+        for view in views.iter_mut() {
+            let items = view.get(&relation.local_key).and_then(|key| related.get(&key.to_string()));
+            let embedded = match relation.kind {
+                RelationKind::BelongsTo => items.and_then(|items| items.first().cloned()).unwrap_or(Value::Null),
+                RelationKind::HasMany => Value::Array(items.cloned().unwrap_or_default()),
+            };
+            view.insert(relation.name.clone(), embedded);
+        }
+    }
+    Ok(())
+}
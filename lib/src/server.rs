@@ -0,0 +1,236 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream;
+use hyper::Server;
+use hyper::server::accept;
+use hyper::service::make_service_fn;
+use routerify::{RequestServiceBuilder, RouterService};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::application::Database;
+use crate::frontend_http::Application;
+
+const DEFAULT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3000);
+
+// how Application::serve listens
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    // serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    // stop gracefully on SIGINT / SIGTERM, turn off when the handle is the only way to stop
+    pub shutdown_on_signal: bool,
+}
+
+// PEM encoded certificate chain and private key
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: SocketAddr::from(DEFAULT_ADDR),
+            tls: None,
+            shutdown_on_signal: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new(addr: SocketAddr) -> Self {
+        ServerConfig { addr, ..Default::default() }
+    }
+
+    // reads RSWEB_ADDR (e.g. `0.0.0.0:8080`), and RSWEB_TLS_CERT / RSWEB_TLS_KEY to enable TLS,
+    // anything unset keeps its default
+    pub fn from_env() -> io::Result<Self> {
+        let mut config = ServerConfig::default();
+        if let Ok(addr) = std::env::var("RSWEB_ADDR") {
+            config.addr = addr.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("RSWEB_ADDR `{}` isn't a socket address", addr)))?;
+        }
+        match (std::env::var_os("RSWEB_TLS_CERT"), std::env::var_os("RSWEB_TLS_KEY")) {
+            (Some(cert), Some(key)) => config.tls = Some(TlsConfig { cert_path: cert.into(), key_path: key.into() }),
+            (None, None) => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "RSWEB_TLS_CERT and RSWEB_TLS_KEY must be set together")),
+        }
+        Ok(config)
+    }
+
+    pub fn with_tls<C: Into<PathBuf>, K: Into<PathBuf>>(mut self, cert_path: C, key_path: K) -> Self {
+        self.tls = Some(TlsConfig { cert_path: cert_path.into(), key_path: key_path.into() });
+        self
+    }
+}
+
+// a running server, await it to wait until it has stopped
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl ServerHandle {
+    // the bound address, useful when the config asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // stop accepting connections and let in-flight requests finish, await the handle to wait for them
+    pub fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.task.poll_unpin(cx).map(|joined| match joined {
+            Ok(result) => result.map_err(io::Error::other),
+            Err(err) => Err(io::Error::other(err)),
+        })
+    }
+}
+
+impl<T> Application<T> where T: Database + 'static + Send + Sync {
+    // bind and start serving in the background, must be called from within a tokio runtime
+    pub async fn serve(self, config: ServerConfig) -> io::Result<ServerHandle> {
+        let router = self.router_builder.build().map_err(io::Error::other)?;
+        let listener = TcpListener::bind(config.addr).await?;
+        let addr = listener.local_addr()?;
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let signal = shutdown_signal(stopped, config.shutdown_on_signal);
+
+        let task = match &config.tls {
+            None => {
+                let service = RouterService::new(router).map_err(io::Error::other)?;
+                let incoming = hyper::server::conn::AddrIncoming::from_listener(listener).map_err(io::Error::other)?;
+                let server = Server::builder(incoming).serve(service).with_graceful_shutdown(signal);
+                println!("App is running on: http://{}", addr);
+                tokio::spawn(server)
+            }
+            Some(tls) => {
+                let acceptor = TlsAcceptor::from(Arc::new(load_tls(tls)?));
+                let builder = Arc::new(RequestServiceBuilder::new(router).map_err(io::Error::other)?);
+                let service = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                    let remote = conn.get_ref().0.peer_addr().unwrap_or(addr);
+                    let service = builder.build(remote);
+                    async move { Ok::<_, Infallible>(service) }
+                });
+                let server = Server::builder(accept::from_stream(tls_incoming(listener, acceptor)))
+                    .serve(service)
+                    .with_graceful_shutdown(signal);
+                println!("App is running on: https://{}", addr);
+                tokio::spawn(server)
+            }
+        };
+
+        Ok(ServerHandle { addr, shutdown: Some(shutdown), task })
+    }
+}
+
+// resolves on a stop through the handle, or on SIGINT / SIGTERM if enabled
+fn shutdown_signal(stopped: oneshot::Receiver<()>, on_signal: bool) -> BoxFuture<'static, ()> {
+    let stopped = stopped.map(|_| ()).boxed();
+    if !on_signal {
+        return stopped;
+    }
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    }.boxed();
+    future::select_all(vec![stopped, interrupt, terminate()]).map(|_| ()).boxed()
+}
+
+#[cfg(unix)]
+fn terminate() -> BoxFuture<'static, ()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => async move {
+            sigterm.recv().await;
+        }.boxed(),
+        Err(_) => future::pending().boxed(),
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate() -> BoxFuture<'static, ()> {
+    future::pending().boxed()
+}
+
+// accept TCP connections and run the TLS handshakes concurrently, so a slow client can't hold up the rest
+fn tls_incoming(listener: TcpListener, acceptor: TlsAcceptor) -> impl futures::Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            // the server dropping the receiver on shutdown also releases the listener
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = sender.closed() => break,
+            };
+            let tcp = match accepted {
+                Ok((tcp, _)) => tcp,
+                Err(err) => {
+                    eprintln!("[webf] error accepting connection: {}", err);
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(tcp).await {
+                    Ok(tls) => {
+                        let _ = sender.send(Ok(tls)).await;
+                    }
+                    Err(err) => eprintln!("[webf] TLS handshake failed: {}", err),
+                }
+            });
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|conn| (conn, receiver))
+    })
+}
+
+fn load_tls(tls: &TlsConfig) -> io::Result<rustls::ServerConfig> {
+    let invalid = |path: &Path, what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), what));
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))?;
+    if certs.is_empty() {
+        return Err(invalid(&tls.cert_path, "no certificates found"));
+    }
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&tls.key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(&tls.key_path, "no private key found"))?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(rustls::Certificate).collect(), rustls::PrivateKey(key))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Method, Request, Response};
+use hyper::header::{HeaderName, HeaderValue, SET_COOKIE};
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, NONCE_LEN, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use rsweb_macros::{DataResource, Fields};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::application::{Database, Filter};
+use crate::auth::cookie;
+use crate::error::Error;
+use crate::middleware::{Flow, Locals, Middleware, RequestInfo, RouteInfo};
+
+// where DbSessions keeps them, add Session to the Migrator to create it and,
+// on DbSql, register it with `with_resource::<Session>()` like any other resource
+pub const SESSIONS_COLLECTION: &str = "_rsweb_sessions";
+// state-changing requests of a session have to echo its token in this header
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, DataResource, Fields)]
+#[rsweb(collection = "_rsweb_sessions")]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub data: HashMap<String, Value>,
+    pub csrf_token: String,
+    // seconds since the unix epoch
+    pub expires_at: i64,
+}
+
+impl Session {
+    fn new(ttl: u64) -> Self {
+        Session { id: Some(random_token()), data: HashMap::new(), csrf_token: random_token(), expires_at: expiry(ttl) }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= now()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() as i64).unwrap_or(0)
+}
+
+fn expiry(ttl: u64) -> i64 {
+    now() + ttl as i64
+}
+
+// 256 random bits, for session ids and CSRF tokens
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("no system randomness");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// doesn't stop at the first difference, so timing doesn't give the token away
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn load(&self, id: &str) -> Result<Option<Session>, Error>;
+    // insert or replace
+    async fn save(&self, session: &Session) -> Result<(), Error>;
+    async fn delete(&self, id: &str) -> Result<(), Error>;
+}
+
+// sessions of a single process, lost on restart
+#[derive(Default)]
+pub struct MemorySessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl MemorySessions {
+    pub fn new() -> Self {
+        MemorySessions::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessions {
+    async fn load(&self, id: &str) -> Result<Option<Session>, Error> {
+        Ok(self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(id).cloned())
+    }
+
+    async fn save(&self, session: &Session) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // expired sessions are dropped as new ones come in
+        sessions.retain(|_, session| !session.is_expired());
+        if let Some(id) = &session.id {
+            sessions.insert(id.clone(), session.clone());
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(id);
+        Ok(())
+    }
+}
+
+// sessions in the SESSIONS_COLLECTION of a data layer, usually the application's data_source
+pub struct DbSessions<DB> {
+    pub data_layer: Arc<DB>,
+}
+
+impl<DB> DbSessions<DB> {
+    pub fn new(data_layer: Arc<DB>) -> Self {
+        DbSessions { data_layer }
+    }
+}
+
+impl<DB: Database> DbSessions<DB> {
+    fn by_id(id: &str) -> DB::Filter {
+        let mut filter = DB::Filter::default();
+        filter.insert("_id", id);
+        filter
+    }
+}
+
+#[async_trait]
+impl<DB: Database + Send + Sync> SessionStore for DbSessions<DB> {
+    async fn load(&self, id: &str) -> Result<Option<Session>, Error> {
+        self.data_layer.retrieve_one(SESSIONS_COLLECTION.to_string(), Self::by_id(id)).await.map_err(Into::into)
+    }
+
+    async fn save(&self, session: &Session) -> Result<(), Error> {
+        let id = session.id.as_deref().unwrap_or_default();
+        let result = self.data_layer.replace_one(SESSIONS_COLLECTION.to_string(), Self::by_id(id), session.clone()).await.map_err(Into::into)?;
+        if result.matched == 0 {
+            self.data_layer.insert_one::<_, Value>(SESSIONS_COLLECTION.to_string(), session.clone()).await.map_err(Into::into)?;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), Error> {
+        self.data_layer.delete_one(SESSIONS_COLLECTION.to_string(), Self::by_id(id)).await.map_err(Into::into)?;
+        Ok(())
+    }
+}
+
+struct State {
+    session: Session,
+    // it came from the store, rather than being started by this request
+    stored: bool,
+    changed: bool,
+    // the id it had before rotate, removed from the store once the request is done
+    rotated_from: Option<String>,
+    destroyed: bool,
+}
+
+// the session of the current request, Context::generate finds it in the request's extensions;
+// changes are saved once the handler is done
+#[derive(Clone)]
+pub struct SessionHandle(Arc<Mutex<State>>);
+
+impl SessionHandle {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.state().session.data.get(key).cloned()?;
+        serde_json::from_value(value).ok()
+    }
+
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<(), Error> {
+        let value = serde_json::to_value(value).map_err(Error::backend)?;
+        let mut state = self.state();
+        state.session.data.insert(key.to_string(), value);
+        state.changed = true;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut state = self.state();
+        if state.session.data.remove(key).is_some() {
+            state.changed = true;
+        }
+    }
+
+    // a new id and CSRF token for the same data, call it on login so an id planted
+    // by someone else doesn't end up signed in
+    pub fn rotate(&self) {
+        let mut state = self.state();
+        if state.stored && state.rotated_from.is_none() {
+            state.rotated_from = state.session.id.clone();
+        }
+        state.session.id = Some(random_token());
+        state.session.csrf_token = random_token();
+        state.changed = true;
+    }
+
+    // drop the session and its cookie, e.g. on logout
+    pub fn destroy(&self) {
+        self.state().destroyed = true;
+    }
+
+    pub fn csrf_token(&self) -> String {
+        self.state().session.csrf_token.clone()
+    }
+}
+
+// server-side sessions, the cookie only carries the signed and optionally encrypted id;
+// a request with a session has to send its CSRF token with anything but GET, HEAD and OPTIONS,
+// the token is sent in the `X-CSRF-Token` response header
+pub struct Sessions<St> {
+    store: St,
+    pub cookie_name: String,
+    signing_key: Vec<u8>,
+    cipher: Option<LessSafeKey>,
+    // seconds a session lasts since it last changed
+    pub ttl: u64,
+    // only sent over HTTPS
+    pub secure: bool,
+}
+
+impl<St: SessionStore> Sessions<St> {
+    pub fn new<S: Into<Vec<u8>>>(store: St, secret: S) -> Self {
+        Sessions {
+            store,
+            cookie_name: "session".to_string(),
+            signing_key: secret.into(),
+            cipher: None,
+            ttl: 24 * 60 * 60,
+            secure: true,
+        }
+    }
+
+    // encrypt the id as well, with a key derived from the secret
+    pub fn encrypted(mut self) -> Self {
+        let mut mac = self.mac();
+        mac.update(b"rsweb session encryption");
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &mac.finalize().into_bytes()).expect("Unreachable");
+        self.cipher = Some(LessSafeKey::new(key));
+        self
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.signing_key).expect("HMAC takes keys of any length")
+    }
+
+    // `<id or nonce and ciphertext>.<signature>`
+    fn encode(&self, id: &str) -> String {
+        let payload = match &self.cipher {
+            Some(cipher) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                SystemRandom::new().fill(&mut nonce).expect("no system randomness");
+                let mut sealed = id.as_bytes().to_vec();
+                cipher.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed).expect("Unreachable");
+                let mut bytes = nonce.to_vec();
+                bytes.extend(sealed);
+                URL_SAFE_NO_PAD.encode(bytes)
+            }
+            None => id.to_string(),
+        };
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    // the id in a cookie value, None if it was tampered with
+    fn decode(&self, value: &str) -> Option<String> {
+        let (payload, signature) = value.rsplit_once('.')?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+        match &self.cipher {
+            Some(cipher) => {
+                let bytes = URL_SAFE_NO_PAD.decode(payload).ok()?;
+                if bytes.len() < NONCE_LEN {
+                    return None;
+                }
+                let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+                let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+                let mut sealed = sealed.to_vec();
+                let id = cipher.open_in_place(nonce, Aad::empty(), &mut sealed).ok()?;
+                String::from_utf8(id.to_vec()).ok()
+            }
+            None => Some(payload.to_string()),
+        }
+    }
+
+    fn set_cookie(&self, value: &str, max_age: u64) -> String {
+        let secure = if self.secure { "; Secure" } else { "" };
+        format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}", self.cookie_name, value, max_age, secure)
+    }
+
+    async fn current(&self, req: &Request<Body>) -> Result<Option<Session>, Error> {
+        let id = match cookie(req, &self.cookie_name).and_then(|value| self.decode(value)) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        Ok(self.store.load(&id).await?.filter(|session| !session.is_expired()))
+    }
+
+    // persist what the request did to its session, returns the headers to add
+    async fn finish(&self, handle: &SessionHandle) -> Result<Vec<(HeaderName, String)>, Error> {
+        let (session, stored, changed, rotated_from, destroyed) = {
+            let state = handle.state();
+            (state.session.clone(), state.stored, state.changed, state.rotated_from.clone(), state.destroyed)
+        };
+        let id = session.id.clone().unwrap_or_default();
+        // a rotated session is stored under its old id until now
+        let stored_id = match rotated_from {
+            Some(previous) => Some(previous),
+            None if stored => Some(id.clone()),
+            None => None,
+        };
+        if destroyed {
+            if let Some(stored_id) = stored_id {
+                self.store.delete(&stored_id).await?;
+            }
+            return Ok(vec![(SET_COOKIE, self.set_cookie("", 0))]);
+        }
+
+        let mut headers = vec![];
+        if changed {
+            if let Some(stored_id) = stored_id.filter(|stored_id| *stored_id != id) {
+                self.store.delete(&stored_id).await?;
+            }
+            self.store.save(&Session { expires_at: expiry(self.ttl), ..session.clone() }).await?;
+            headers.push((SET_COOKIE, self.set_cookie(&self.encode(&id), self.ttl)));
+        }
+        if stored || changed {
+            headers.push((HeaderName::from_static(CSRF_HEADER), session.csrf_token));
+        }
+        Ok(headers)
+    }
+}
+
+#[async_trait]
+impl<St: SessionStore> Middleware for Sessions<St> {
+    async fn before(&self, mut req: Request<Body>, _route: &RouteInfo) -> Result<Flow, Error> {
+        let existing = self.current(&req).await?;
+        let stored = existing.is_some();
+        let session = existing.unwrap_or_else(|| Session::new(self.ttl));
+
+        let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        if stored && !safe {
+            let sent = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok()).unwrap_or_default();
+            if !same_token(sent, &session.csrf_token) {
+                return Err(Error::Forbidden);
+            }
+        }
+
+        let handle = SessionHandle(Arc::new(Mutex::new(State { session, stored, changed: false, rotated_from: None, destroyed: false })));
+        if let Some(locals) = req.extensions().get::<Locals>() {
+            locals.insert(handle.clone());
+        }
+        req.extensions_mut().insert(handle);
+        Ok(Flow::Continue(req))
+    }
+
+    async fn after(&self, mut res: Response<Body>, req: &RequestInfo) -> Response<Body> {
+        let handle = match req.locals.get::<SessionHandle>() {
+            Some(handle) => handle,
+            None => return res,
+        };
+        match self.finish(&handle).await {
+            Ok(headers) => {
+                for (name, value) in headers {
+                    if let Ok(value) = HeaderValue::from_str(&value) {
+                        res.headers_mut().append(name, value);
+                    }
+                }
+                res
+            }
+            Err(err) => err.into_response(false),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+
+pub use regex::Regex;
+
+use crate::error::Error;
+
+// checks a resource before it is written, derive it with `#[validate(...)]` rules on the fields
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+// failed rules by field name, one message per field
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationErrors {
+    pub fields: HashMap<String, String>,
+}
+
+impl ValidationErrors {
+    // a field keeps the message of the first rule it failed
+    pub fn add<F: Into<String>, M: Into<String>>(&mut self, field: F, message: M) {
+        self.fields.entry(field.into()).or_insert_with(|| message.into());
+    }
+
+    pub fn check<F: Into<String>>(&mut self, field: F, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.add(field, message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(errors: ValidationErrors) -> Self {
+        Error::Unprocessable(errors.fields)
+    }
+}
+
+// anything the `length` rule applies to, strings count characters
+pub trait Length {
+    fn length(&self) -> usize;
+}
+
+impl Length for str {
+    fn length(&self) -> usize {
+        self.chars().count()
+    }
+}
+
+impl Length for String {
+    fn length(&self) -> usize {
+        self.as_str().length()
+    }
+}
+
+impl<T> Length for [T] {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> Length for Vec<T> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<K, V, H> Length for HashMap<K, V, H> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T, H> Length for HashSet<T, H> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<K, V> Length for BTreeMap<K, V> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> Length for BTreeSet<T> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+pub fn length<T: Length + ?Sized>(value: &T, min: Option<usize>, max: Option<usize>) -> Result<(), String> {
+    let length = value.length();
+    match (min, max) {
+        (Some(min), _) if length < min => Err(format!("length must be at least {}", min)),
+        (_, Some(max)) if length > max => Err(format!("length must be at most {}", max)),
+        _ => Ok(()),
+    }
+}
+
+// numbers are compared as f64, the derive converts them with `as`
+pub fn range(value: f64, min: Option<f64>, max: Option<f64>) -> Result<(), String> {
+    match (min, max) {
+        (Some(min), _) if value < min => Err(format!("must be at least {}", min)),
+        (_, Some(max)) if value > max => Err(format!("must be at most {}", max)),
+        _ => Ok(()),
+    }
+}
+
+// the whole value has to match, `regex` being what `anchored` made of the pattern; the derive
+// compiles each field's pattern once, into a static of its own
+pub fn pattern<S: AsRef<str> + ?Sized>(value: &S, regex: &Regex) -> Result<(), String> {
+    if regex.is_match(value.as_ref()) {
+        Ok(())
+    } else {
+        let anchored = regex.as_str();
+        let pattern = anchored.strip_prefix("^(?:").and_then(|rest| rest.strip_suffix(")$")).unwrap_or(anchored);
+        Err(format!("must match `{}`", pattern))
+    }
+}
+
+// a pattern matching whole values, anchors in it are optional
+pub fn anchored(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+// a single `@` with something before it and a dotted domain after it, no whitespace
+pub fn email<S: AsRef<str> + ?Sized>(value: &S) -> Result<(), String> {
+    let value = value.as_ref();
+    let valid = match value.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|label| !label.is_empty())
+                && !value.chars().any(char::is_whitespace)
+                && value.len() <= 254
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err("must be an email address".to_string())
+    }
+}
+
+// an absolute URL with a host
+pub fn url<S: AsRef<str> + ?Sized>(value: &S) -> Result<(), String> {
+    match url::Url::parse(value.as_ref()) {
+        Ok(parsed) if parsed.has_host() => Ok(()),
+        _ => Err("must be a URL".to_string()),
+    }
+}
+
+pub fn one_of<T: PartialEq<L> + ?Sized, L: Display>(value: &T, allowed: &[L]) -> Result<(), String> {
+    if allowed.iter().any(|candidate| value == candidate) {
+        Ok(())
+    } else {
+        let allowed: Vec<String> = allowed.iter().map(ToString::to_string).collect();
+        Err(format!("must be one of: {}", allowed.join(", ")))
+    }
+}
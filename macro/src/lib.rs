@@ -11,13 +11,14 @@ use ::quote::{
     ToTokens,
 };
 use ::syn::{*,
+            ext::IdentExt,
             parse::{Parse, Parser, ParseStream},
             punctuated::Punctuated,
             spanned::Spanned,
             Result,
 };
 
-#[proc_macro_derive(Fields)] pub
+#[proc_macro_derive(Fields, attributes(rsweb))] pub
 fn rule_system_derive (input: TokenStream)
                        -> TokenStream
 {
@@ -28,11 +29,10 @@ fn rule_system_derive (input: TokenStream)
     })
 }
 
-fn impl_my_trait (ast: DeriveInput)
-                  -> Result<TokenStream2>
+fn named_fields (data: Data)
+                 -> Result<FieldsNamed>
 {Ok({
-    let name = ast.ident;
-    let fields = match ast.data {
+    match data {
         | Data::Enum(DataEnum { enum_token: token::Enum { span }, .. })
         | Data::Union(DataUnion { union_token: token::Union { span }, .. })
         => {
@@ -52,29 +52,863 @@ fn impl_my_trait (ast: DeriveInput)
                 "Expected a `struct` with named fields",
             ));
         },
-    };
+    }
+})}
+
+fn impl_my_trait (ast: DeriveInput)
+                  -> Result<TokenStream2>
+{Ok({
+    let name = ast.ident;
+    let fields = named_fields(ast.data)?;
 
-    let data_expanded_members = fields.named.into_iter().map(|field| {
+    let rename_all = serde_rename_all(&ast.attrs)?;
+
+    let mut parsed = vec![];
+    for field in fields.named {
+        let serde = SerdeField::parse(&field.attrs)?;
+        let rsweb = RswebField::parse(&field.attrs)?;
+        if !serde.skip {
+            parsed.push((field, serde, rsweb));
+        }
+    }
+    // marking any field filterable (or sortable) restricts it to the marked fields
+    let filter_opt_in = parsed.iter().any(|(_, _, rsweb)| rsweb.filterable);
+    let sort_opt_in = parsed.iter().any(|(_, _, rsweb)| rsweb.sortable);
+
+    let mut data_expanded_members = vec![];
+    for (field, serde, rsweb) in parsed {
+        let ty = &field.ty;
         let field_name = field.ident.expect("Unreachable");
         let span = field_name.span();
+
+        if serde.flatten {
+            // the nested struct's fields are inlined, maps and other opaque types contribute none
+            data_expanded_members.push(quote_spanned! { span=>
+                fields.extend({
+                    #[allow(unused_imports)]
+                    use ::rsweb_lib::application::{NestedFields as _, OpaqueFields as _};
+                    (&::rsweb_lib::application::NestedProbe::<#ty>(::std::marker::PhantomData)).nested_fields()
+                }.unwrap_or_default());
+            });
+            continue;
+        }
+
+        let wire_name = match serde.rename {
+            | Some(rename) => rename,
+            | None => apply_rename_all(rename_all.as_deref(), &field_name.unraw().to_string()),
+        };
         let field_name_stringified =
-            LitStr::new(&field_name.to_string(), span)
+            LitStr::new(&wire_name, span)
             ;
-        quote_spanned! { span=>
-            Field {
+        let (field_type, nullable) = match unwrap_generic(ty, "Option") {
+            | Some(inner) => (describe_type(inner), true),
+            | None => (describe_type(ty), false),
+        };
+        // serde fills in a missing Option with None
+        let optional = nullable || serde.default || serde.skip_serializing_if;
+        // private fields can't be probed through filters or ordering either
+        let private = rsweb.hidden || rsweb.write_only;
+        let filterable = !private && (rsweb.filterable || !filter_opt_in);
+        let sortable = !private && (rsweb.sortable || !sort_opt_in);
+        let RswebField { readonly, hidden, write_only, unique, .. } = rsweb;
+        let indexed = rsweb.index || unique;
+        data_expanded_members.push(quote_spanned! { span=>
+            fields.push(::rsweb_lib::application::Field {
                 name: #field_name_stringified.to_string(),
-                is_bool: false,
-                is_num: false,
+                field_type: #field_type,
+                nullable: #nullable,
+                optional: #optional,
+                filterable: #filterable,
+                sortable: #sortable,
+                readonly: #readonly,
+                hidden: #hidden,
+                write_only: #write_only,
+                indexed: #indexed,
+                unique: #unique,
+            });
+        });
+    }
+    quote! {
+        impl ::rsweb_lib::application::Fields for #name {
+            fn fields() -> Vec<::rsweb_lib::application::Field> {
+                let mut fields = vec![];
+                #(#data_expanded_members)*
+                fields
+            }
+        }
+    }
+})}
+
+/// The `#[serde(...)]` field attributes that change how a field is stored.
+#[derive(Default)]
+struct SerdeField {
+    rename: Option<String>,
+    skip: bool,
+    skip_serializing_if: bool,
+    flatten: bool,
+    default: bool,
+}
+
+impl SerdeField {
+    fn parse (attrs: &[Attribute])
+              -> Result<Self>
+    {
+        let mut parsed = SerdeField::default();
+        for meta in serde_metas(attrs)? {
+            match &meta {
+                | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+                if path.is_ident("rename")
+                => parsed.rename = Some(lit.value()),
+
+                // only the serialized name ends up in storage and responses
+                | NestedMeta::Meta(Meta::List(MetaList { path, nested, .. }))
+                if path.is_ident("rename")
+                => parsed.rename = serialize_name(nested).or(parsed.rename),
+
+                | NestedMeta::Meta(Meta::Path(path))
+                if path.is_ident("skip") || path.is_ident("skip_serializing")
+                => parsed.skip = true,
+
+                | NestedMeta::Meta(meta)
+                if meta.path().is_ident("skip_serializing_if")
+                => parsed.skip_serializing_if = true,
+
+                | NestedMeta::Meta(Meta::Path(path))
+                if path.is_ident("flatten")
+                => parsed.flatten = true,
+
+                | NestedMeta::Meta(meta)
+                if meta.path().is_ident("default")
+                => parsed.default = true,
+
+                | _ => {},
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// The `#[rsweb(...)]` field attributes, shared by all the derives.
+#[derive(Default)]
+struct RswebField {
+    id: bool,
+    filterable: bool,
+    sortable: bool,
+    readonly: bool,
+    hidden: bool,
+    write_only: bool,
+    index: bool,
+    unique: bool,
+    /// `belongs_to = "User"`, the field holds the id of that resource
+    belongs_to: Option<LitStr>,
+}
+
+impl RswebField {
+    fn parse (attrs: &[Attribute])
+              -> Result<Self>
+    {
+        let mut parsed = RswebField::default();
+        for meta in rsweb_metas(attrs)? {
+            if let NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. })) = &meta {
+                if path.is_ident("belongs_to") {
+                    parsed.belongs_to = Some(lit.clone());
+                    continue;
+                }
+            }
+            let flag = match &meta {
+                | NestedMeta::Meta(Meta::Path(path)) => path.get_ident().map(|ident| ident.to_string()),
+                | _ => None,
+            };
+            match flag.as_deref() {
+                | Some("id") => parsed.id = true,
+                | Some("filterable") => parsed.filterable = true,
+                | Some("sortable") => parsed.sortable = true,
+                | Some("readonly") => parsed.readonly = true,
+                | Some("hidden") => parsed.hidden = true,
+                | Some("write_only") => parsed.write_only = true,
+                | Some("index") => parsed.index = true,
+                | Some("unique") => parsed.unique = true,
+                | _ => return Err(Error::new(meta.span(), "Unknown `rsweb` field attribute")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// The `#[rsweb(...)]` container attributes.
+#[derive(Default)]
+struct RswebContainer {
+    collection: Option<String>,
+    id_generator: Option<LitStr>,
+    has_many: Vec<HasMany>,
+    validate: bool,
+}
+
+/// `has_many(name = "comments", resource = "Comment", foreign_key = "movie_id")`,
+/// the foreign key defaults to the snake_case struct name followed by `_id`.
+struct HasMany {
+    name: LitStr,
+    resource: LitStr,
+    foreign_key: Option<LitStr>,
+}
+
+impl HasMany {
+    fn parse (meta: &MetaList)
+              -> Result<Self>
+    {
+        let (mut name, mut resource, mut foreign_key) = (None, None, None);
+        for item in &meta.nested {
+            match item {
+                | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. })) => {
+                    match path.get_ident().map(|ident| ident.to_string()).as_deref() {
+                        | Some("name") => name = Some(lit.clone()),
+                        | Some("resource") => resource = Some(lit.clone()),
+                        | Some("foreign_key") => foreign_key = Some(lit.clone()),
+                        | _ => return Err(Error::new(path.span(), "Expected `name`, `resource` or `foreign_key`")),
+                    }
+                },
+                | other => return Err(Error::new(other.span(), "Expected `key = \"...\"`")),
+            }
+        }
+        match (name, resource) {
+            | (Some(name), Some(resource)) => Ok(HasMany { name, resource, foreign_key }),
+            | _ => Err(Error::new(meta.span(), "`has_many` needs a `name` and a `resource`")),
+        }
+    }
+}
+
+impl RswebContainer {
+    fn parse (attrs: &[Attribute])
+              -> Result<Self>
+    {
+        let mut parsed = RswebContainer::default();
+        for meta in rsweb_metas(attrs)? {
+            match &meta {
+                | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+                if path.is_ident("collection")
+                => parsed.collection = Some(lit.value()),
+
+                | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+                if path.is_ident("id_generator")
+                => parsed.id_generator = Some(lit.clone()),
+
+                | NestedMeta::Meta(Meta::List(list))
+                if list.path.is_ident("has_many")
+                => parsed.has_many.push(HasMany::parse(list)?),
+
+                | NestedMeta::Meta(Meta::Path(path))
+                if path.is_ident("validate")
+                => parsed.validate = true,
+
+                | _ => return Err(Error::new(meta.span(), "Unknown `rsweb` container attribute")),
             }
         }
-    });
+        Ok(parsed)
+    }
+}
+
+/// The items of every `#[rsweb(...)]` attribute.
+fn rsweb_metas (attrs: &[Attribute])
+                -> Result<Vec<NestedMeta>>
+{
+    let mut metas = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("rsweb")) {
+        match attr.parse_meta()? {
+            | Meta::List(list) => metas.extend(list.nested),
+            | other => return Err(Error::new(other.span(), "Expected `#[rsweb(...)]`")),
+        }
+    }
+    Ok(metas)
+}
+
+/// Container level `#[serde(rename_all = "...")]`.
+fn serde_rename_all (attrs: &[Attribute])
+                     -> Result<Option<String>>
+{
+    let mut rename_all = None;
+    for meta in serde_metas(attrs)? {
+        match &meta {
+            | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+            if path.is_ident("rename_all")
+            => rename_all = Some(lit.value()),
+
+            | NestedMeta::Meta(Meta::List(MetaList { path, nested, .. }))
+            if path.is_ident("rename_all")
+            => rename_all = serialize_name(nested).or(rename_all),
+
+            | _ => {},
+        }
+    }
+    Ok(rename_all)
+}
+
+/// The items of every `#[serde(...)]` attribute.
+fn serde_metas (attrs: &[Attribute])
+                -> Result<Vec<NestedMeta>>
+{
+    let mut metas = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("serde")) {
+        match attr.parse_meta()? {
+            | Meta::List(list) => metas.extend(list.nested),
+            | other => return Err(Error::new(other.span(), "Expected `#[serde(...)]`")),
+        }
+    }
+    Ok(metas)
+}
+
+/// `serialize = "..."` out of `rename(serialize = "...", deserialize = "...")`.
+fn serialize_name (nested: &Punctuated<NestedMeta, Token![,]>)
+                   -> Option<String>
+{
+    nested.iter().find_map(|meta| match meta {
+        | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+        if path.is_ident("serialize")
+        => Some(lit.value()),
+        | _ => None,
+    })
+}
+
+/// Same conversions serde applies to snake_case field names.
+fn apply_rename_all (rule: Option<&str>, field: &str)
+                     -> String
+{
+    let pascal = || field.split('_').map(|word| {
+        let mut chars = word.chars();
+        match chars.next() {
+            | Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+            | None => String::new(),
+        }
+    }).collect::<String>();
+    match rule {
+        | Some("UPPERCASE") | Some("SCREAMING_SNAKE_CASE") => field.to_ascii_uppercase(),
+        | Some("PascalCase") => pascal(),
+        | Some("camelCase") => {
+            let pascal = pascal();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                | Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+                | None => String::new(),
+            }
+        },
+        | Some("kebab-case") => field.replace('_', "-"),
+        | Some("SCREAMING-KEBAB-CASE") => field.replace('_', "-").to_ascii_uppercase(),
+        | _ => field.to_string(),
+    }
+}
+
+/// The collection is the plural of the snake_case struct name, see
+/// `pluralize`; set `#[rsweb(collection = "...")]` for irregular nouns.
+#[proc_macro_derive(DataResource, attributes(rsweb))] pub
+fn data_resource_derive (input: TokenStream)
+                         -> TokenStream
+{
+    let ast = parse_macro_input!(input as _);
+    TokenStream::from(match impl_data_resource(ast) {
+        | Ok(it) => it,
+        | Err(err) => err.to_compile_error(),
+    })
+}
+
+fn impl_data_resource (ast: DeriveInput)
+                       -> Result<TokenStream2>
+{Ok({
+    let name = ast.ident;
+    let container = RswebContainer::parse(&ast.attrs)?;
+    let collection = container.collection
+        .unwrap_or_else(|| pluralize(&snake_case(&name.to_string())));
+    let fields = named_fields(ast.data)?;
+
+    // the field marked `#[rsweb(id)]`, or else the one called `id`
+    let mut marked = vec![];
+    for field in &fields.named {
+        if RswebField::parse(&field.attrs)?.id {
+            marked.push(field);
+        }
+    }
+    let id_field = match marked.as_slice() {
+        | [field] => *field,
+        | [] => match fields.named.iter().find(|field| field.ident.as_ref().map(|ident| ident == "id").unwrap_or(false)) {
+            | Some(field) => field,
+            | None => return Err(Error::new(
+                name.span(),
+                "Expected a field marked `#[rsweb(id)]`",
+            )),
+        },
+        | [_, second, ..] => return Err(Error::new(
+            second.span(),
+            "Only one field can be marked `#[rsweb(id)]`",
+        )),
+    };
+    let id = id_field.ident.as_ref().expect("Unreachable");
+    // the handlers and data layers look items up by `_id`, whatever the Rust name
+    let id_wire_name = match SerdeField::parse(&id_field.attrs)?.rename {
+        | Some(rename) => rename,
+        | None => apply_rename_all(serde_rename_all(&ast.attrs)?.as_deref(), &id.unraw().to_string()),
+    };
+    if id_wire_name != "_id" {
+        return Err(Error::new(
+            id.span(),
+            "The id field has to be serialized as `_id`, add `#[serde(rename = \"_id\")]`",
+        ));
+    }
+
+    // a plain id field is only overwritten when there is a value to put in it
+    let (id_type, get_id, set_id) = match unwrap_generic(&id_field.ty, "Option") {
+        | Some(inner) => (
+            inner,
+            quote!(self.#id.clone()),
+            quote!(self.#id = id;),
+        ),
+        | None => (
+            &id_field.ty,
+            quote!(Some(self.#id.clone())),
+            quote!(if let Some(id) = id { self.#id = id; }),
+        ),
+    };
+
+    // without an `id_generator` the id type's own default is used
+    let next_id = match container.id_generator {
+        | None => quote!(),
+        | Some(generator) => {
+            let generator = match generator.value().as_str() {
+                | "uuid_v4" => quote!(::rsweb_lib::ids::UuidV4),
+                | "uuid_v7" => quote!(::rsweb_lib::ids::UuidV7),
+                | "ulid" => quote!(::rsweb_lib::ids::UlidGen),
+                // integer ids are counted per collection by the data layer, which survives restarts
+                // and is shared by every replica
+                | "backend" | "sequence" => quote!(::rsweb_lib::ids::BackendAssigned),
+                // path to a value implementing IdGenerator, e.g. a static
+                | _ => generator.parse::<ExprPath>()?.into_token_stream(),
+            };
+            quote! {
+                fn next_id() -> Option<Self::Id> {
+                    use ::rsweb_lib::ids::IdGenerator as _;
+                    (#generator).next_id()
+                }
+            }
+        },
+    };
+    // `#[validate(...)]` rules only exist next to `#[derive(Validate)]`, a hand-written impl is opted into
+    let validates = container.validate || fields.named.iter()
+        .any(|field| field.attrs.iter().any(|attr| attr.path.is_ident("validate")));
+    let validate_data = if validates {
+        quote! {
+            fn validate_data(&self) -> ::std::result::Result<(), ::rsweb_lib::validate::ValidationErrors> {
+                ::rsweb_lib::validate::Validate::validate(self)
+            }
+        }
+    } else {
+        quote!()
+    };
+    // relations `?expand=` can embed, named the way clients see them
+    let rename_all = serde_rename_all(&ast.attrs)?;
+    let mut relations = vec![];
+    for field in &fields.named {
+        if let Some(resource) = RswebField::parse(&field.attrs)?.belongs_to {
+            let ident = field.ident.as_ref().expect("Unreachable");
+            let local_key = match SerdeField::parse(&field.attrs)?.rename {
+                | Some(rename) => rename,
+                | None => apply_rename_all(rename_all.as_deref(), &ident.unraw().to_string()),
+            };
+            // `user_id` embeds as `user`, a key without the suffix is replaced by what it refers to
+            let relation = local_key.strip_suffix("_id")
+                .or_else(|| local_key.strip_suffix("Id"))
+                .unwrap_or(&local_key)
+                .to_string();
+            relations.push((relation, quote!(BelongsTo), local_key, "_id".to_string(), resource.parse::<Type>()?));
+        }
+    }
+    for has_many in &container.has_many {
+        let foreign_key = has_many.foreign_key.as_ref()
+            .map(LitStr::value)
+            .unwrap_or_else(|| format!("{}_id", snake_case(&name.to_string())));
+        relations.push((has_many.name.value(), quote!(HasMany), "_id".to_string(), foreign_key, has_many.resource.parse::<Type>()?));
+    }
+    let related = if relations.is_empty() {
+        quote! {
+            impl<S> ::rsweb_lib::relations::Related<S> for #name where S: ::rsweb_lib::frontend_http::Context + Send + Sync {}
+        }
+    } else {
+        let entries = relations.iter().map(|(relation, kind, local_key, foreign_key, _)| quote! {
+            ::rsweb_lib::relations::Relation {
+                name: #relation.to_string(),
+                kind: ::rsweb_lib::relations::RelationKind::#kind,
+                local_key: #local_key.to_string(),
+                foreign_key: #foreign_key.to_string(),
+            }
+        });
+        // the related resources are rendered with their own hooks
+        let bounds = relations.iter().map(|(.., resource)| quote! {
+            #resource: ::rsweb_lib::frontend_http::DataResource
+                + ::rsweb_lib::application::Fields
+                + ::rsweb_lib::frontend_http::Protected<S>
+                + Send + Sync
+        });
+        let arms = relations.iter().map(|(relation, _, _, foreign_key, resource)| quote! {
+            #relation => ::rsweb_lib::relations::load::<#resource, S, DB>(data_layer, #foreign_key, keys, ctx).await
+        });
+        quote! {
+            impl<S> ::rsweb_lib::relations::Related<S> for #name
+                where S: ::rsweb_lib::frontend_http::Context + Send + Sync, #(#bounds),*
+            {
+                fn relations() -> ::std::vec::Vec<::rsweb_lib::relations::Relation> {
+                    vec![#(#entries),*]
+                }
+
+                fn load_related<'a, DB: ::rsweb_lib::application::Database + Send + Sync>(name: &'a str, data_layer: &'a DB, keys: ::rsweb_lib::relations::Keys, ctx: &'a S)
+                    -> ::rsweb_lib::relations::RelatedFuture<'a>
+                {
+                    ::std::boxed::Box::pin(async move {
+                        match name {
+                            #(#arms,)*
+                            _ => Err(::rsweb_lib::relations::unknown_relation(name)),
+                        }
+                    })
+                }
+            }
+        }
+    };
     quote! {
-        impl Fields for #name {
-            fn fields() -> Vec<Field> {
-                vec![
-                    #(#data_expanded_members ,)*
-                ]
+        impl ::rsweb_lib::frontend_http::DataResource for #name {
+            type Id = #id_type;
+
+            fn get_collection_name() -> String {
+                #collection.to_string()
             }
+
+            fn get_id(&self) -> Option<Self::Id> {
+                #get_id
+            }
+
+            fn set_id(&mut self, id: Option<Self::Id>) {
+                #set_id
+            }
+
+            #next_id
+
+            #validate_data
         }
+
+        #related
     }
 })}
+
+/// `MovieCategory` is `movie_category`; a run of capitals is one word, so
+/// `HTTPLog` is `http_log`, with its last capital starting the next word.
+fn snake_case (name: &str)
+               -> String
+{
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_word = !chars[i - 1].is_uppercase() && chars[i - 1] != '_';
+            let ends_acronym = chars[i - 1].is_uppercase() && chars.get(i + 1).map(|next| next.is_lowercase()).unwrap_or(false);
+            if after_word || ends_acronym {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// Plural of an English noun, good enough for collection names: a consonant
+/// followed by `y` becomes `ies`, nouns ending in `s`, `x`, `z`, `ch` or `sh`
+/// get `es` and the rest get `s`. Irregular nouns, e.g. `person` or `child`,
+/// come out wrong and need `#[rsweb(collection = "...")]`.
+fn pluralize (word: &str)
+              -> String
+{
+    let consonant_y = word.ends_with('y')
+        && !word.ends_with("ay") && !word.ends_with("ey") && !word.ends_with("oy") && !word.ends_with("uy");
+    if consonant_y {
+        format!("{}ies", &word[..word.len() - 1])
+    } else if ["s", "x", "z", "ch", "sh"].iter().any(|suffix| word.ends_with(suffix)) {
+        format!("{}es", word)
+    } else {
+        format!("{}s", word)
+    }
+}
+
+/// `T` if `ty` is `wrapper<T>`
+fn unwrap_generic<'ty> (ty: &'ty Type, wrapper: &str)
+                        -> Option<&'ty Type>
+{
+    let segment = match ty {
+        | Type::Path(TypePath { qself: None, path }) => path.segments.last()?,
+        | _ => return None,
+    };
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        | PathArguments::AngleBracketed(args) => match args.args.first()? {
+            | GenericArgument::Type(inner) => Some(inner),
+            | _ => None,
+        },
+        | _ => None,
+    }
+}
+
+/// Expression building the `FieldType` of `ty`, judged by its name since
+/// the derive can't resolve types.
+fn describe_type (ty: &Type)
+                  -> TokenStream2
+{
+    let field_type = quote!(::rsweb_lib::application::FieldType);
+    match ty {
+        | Type::Reference(TypeReference { elem, .. })
+        | Type::Paren(TypeParen { elem, .. })
+        | Type::Group(TypeGroup { elem, .. })
+        => return describe_type(elem),
+
+        | Type::Array(TypeArray { elem, .. })
+        | Type::Slice(TypeSlice { elem, .. })
+        => {
+            let inner = describe_type(elem);
+            return quote!(#field_type::Array(Box::new(#inner)));
+        },
+
+        | _ => {},
+    }
+
+    let ident = match ty {
+        | Type::Path(TypePath { qself: None, path }) => {
+            path.segments.last().map(|segment| segment.ident.to_string())
+        },
+        | _ => None,
+    };
+    match ident.as_deref() {
+        | Some("u8") | Some("u16") | Some("u32") | Some("u64") | Some("u128") | Some("usize")
+        | Some("i8") | Some("i16") | Some("i32") | Some("i64") | Some("i128") | Some("isize")
+        => quote!(#field_type::Int),
+
+        | Some("f32") | Some("f64")
+        => quote!(#field_type::Float),
+
+        | Some("bool")
+        => quote!(#field_type::Bool),
+
+        | Some("String") | Some("str") | Some("char")
+        => quote!(#field_type::String),
+
+        | Some(wrapper @ "Vec") | Some(wrapper @ "VecDeque") | Some(wrapper @ "HashSet") | Some(wrapper @ "BTreeSet")
+        => {
+            let inner = describe_type(unwrap_generic(ty, wrapper).expect("Unreachable"));
+            quote!(#field_type::Array(Box::new(#inner)))
+        },
+
+        | Some(wrapper @ "Box") | Some(wrapper @ "Rc") | Some(wrapper @ "Arc") | Some(wrapper @ "Option")
+        => describe_type(unwrap_generic(ty, wrapper).expect("Unreachable")),
+
+        | _
+        => quote! {
+            #field_type::Object({
+                #[allow(unused_imports)]
+                use ::rsweb_lib::application::{NestedFields as _, OpaqueFields as _};
+                (&::rsweb_lib::application::NestedProbe::<#ty>(::std::marker::PhantomData)).nested_fields()
+            })
+        },
+    }
+}
+
+#[proc_macro_derive(Validate, attributes(validate))] pub
+fn validate_derive (input: TokenStream)
+                    -> TokenStream
+{
+    let ast = parse_macro_input!(input as _);
+    TokenStream::from(match impl_validate(ast) {
+        | Ok(it) => it,
+        | Err(err) => err.to_compile_error(),
+    })
+}
+
+fn impl_validate (ast: DeriveInput)
+                  -> Result<TokenStream2>
+{Ok({
+    let name = ast.ident;
+    let rename_all = serde_rename_all(&ast.attrs)?;
+    let fields = named_fields(ast.data)?;
+
+    let mut checks = vec![];
+    for field in fields.named {
+        let rules = Rule::parse_all(&field.attrs)?;
+        if rules.is_empty() {
+            continue;
+        }
+        let ident = field.ident.expect("Unreachable");
+        let span = ident.span();
+        // errors are reported under the name clients send the field as
+        let wire_name = match SerdeField::parse(&field.attrs)?.rename {
+            | Some(rename) => rename,
+            | None => apply_rename_all(rename_all.as_deref(), &ident.unraw().to_string()),
+        };
+        let wire_name = LitStr::new(&wire_name, span);
+        let rules = rules.iter().map(|rule| {
+            let result = rule.to_check();
+            quote_spanned! { span=> errors.check(#wire_name, #result); }
+        });
+        // an Option field is only checked when it holds a value
+        checks.push(match unwrap_generic(&field.ty, "Option") {
+            | Some(_) => quote_spanned! { span=>
+                if let Some(value) = &self.#ident {
+                    #(#rules)*
+                }
+            },
+            | None => quote_spanned! { span=>
+                {
+                    let value = &self.#ident;
+                    #(#rules)*
+                }
+            },
+        });
+    }
+    quote! {
+        impl ::rsweb_lib::validate::Validate for #name {
+            fn validate(&self) -> ::std::result::Result<(), ::rsweb_lib::validate::ValidationErrors> {
+                #[allow(unused_mut)]
+                let mut errors = ::rsweb_lib::validate::ValidationErrors::default();
+                #(#checks)*
+                errors.into_result()
+            }
+        }
+    }
+})}
+
+/// One rule out of a `#[validate(...)]` field attribute.
+enum Rule {
+    Length { min: Option<LitInt>, max: Option<LitInt> },
+    Range { min: Option<TokenStream2>, max: Option<TokenStream2> },
+    Regex(LitStr),
+    Email,
+    Url,
+    OneOf(Vec<Lit>),
+    Custom(ExprPath),
+}
+
+impl Rule {
+    fn parse_all (attrs: &[Attribute])
+                  -> Result<Vec<Self>>
+    {
+        let mut rules = vec![];
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("validate")) {
+            let nested = match attr.parse_meta()? {
+                | Meta::List(list) => list.nested,
+                | other => return Err(Error::new(other.span(), "Expected `#[validate(...)]`")),
+            };
+            for meta in nested {
+                rules.push(Rule::parse(&meta)?);
+            }
+        }
+        Ok(rules)
+    }
+
+    fn parse (meta: &NestedMeta)
+              -> Result<Self>
+    {
+        Ok(match meta {
+            | NestedMeta::Meta(Meta::Path(path)) if path.is_ident("email") => Rule::Email,
+            | NestedMeta::Meta(Meta::Path(path)) if path.is_ident("url") => Rule::Url,
+
+            | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+            if path.is_ident("regex")
+            => {
+                // anchored the way validate::anchored compiles it, so a bad pattern fails the build
+                if let Err(err) = ::regex::Regex::new(&format!("^(?:{})$", lit.value())) {
+                    return Err(Error::new(lit.span(), format!("Invalid regex: {}", err)));
+                }
+                Rule::Regex(lit.clone())
+            },
+
+            | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(lit), .. }))
+            if path.is_ident("custom")
+            => Rule::Custom(lit.parse()?),
+
+            | NestedMeta::Meta(Meta::List(MetaList { path, nested, .. }))
+            if path.is_ident("length")
+            => {
+                let (mut min, mut max) = (None, None);
+                for (key, lit) in bounds(nested)? {
+                    let bound = match lit {
+                        | Lit::Int(int) => int,
+                        | other => return Err(Error::new(other.span(), "Expected an integer")),
+                    };
+                    if key == "min" { min = Some(bound) } else { max = Some(bound) }
+                }
+                Rule::Length { min, max }
+            },
+
+            | NestedMeta::Meta(Meta::List(MetaList { path, nested, .. }))
+            if path.is_ident("range")
+            => {
+                let (mut min, mut max) = (None, None);
+                for (key, lit) in bounds(nested)? {
+                    let bound = match lit {
+                        | Lit::Int(int) => quote!(#int as f64),
+                        | Lit::Float(float) => quote!(#float as f64),
+                        // attribute literals can't be negative, so those are written as strings
+                        | Lit::Str(text) => match text.value().parse::<f64>() {
+                            | Ok(number) => quote!(#number),
+                            | Err(_) => return Err(Error::new(text.span(), "Expected a number")),
+                        },
+                        | other => return Err(Error::new(other.span(), "Expected a number")),
+                    };
+                    if key == "min" { min = Some(bound) } else { max = Some(bound) }
+                }
+                Rule::Range { min, max }
+            },
+
+            | NestedMeta::Meta(Meta::List(MetaList { path, nested, .. }))
+            if path.is_ident("one_of")
+            => Rule::OneOf(nested.iter().map(|item| match item {
+                | NestedMeta::Lit(lit) => Ok(lit.clone()),
+                | other => Err(Error::new(other.span(), "Expected a literal")),
+            }).collect::<Result<_>>()?),
+
+            | _ => return Err(Error::new(meta.span(), "Unknown `validate` rule")),
+        })
+    }
+
+    /// Expression evaluating the rule against `value`, a reference to the field's value.
+    fn to_check (&self)
+                 -> TokenStream2
+    {
+        let option = |bound: &Option<TokenStream2>| match bound {
+            | Some(bound) => quote!(::std::option::Option::Some(#bound)),
+            | None => quote!(::std::option::Option::None),
+        };
+        match self {
+            | Rule::Length { min, max } => {
+                let min = option(&min.as_ref().map(|min| quote!(#min)));
+                let max = option(&max.as_ref().map(|max| quote!(#max)));
+                quote!(::rsweb_lib::validate::length(value, #min, #max))
+            },
+            | Rule::Range { min, max } => {
+                let (min, max) = (option(min), option(max));
+                quote!(::rsweb_lib::validate::range(*value as f64, #min, #max))
+            },
+            | Rule::Regex(pattern) => quote!({
+                static REGEX: ::std::sync::OnceLock<::rsweb_lib::validate::Regex> = ::std::sync::OnceLock::new();
+                let regex = REGEX.get_or_init(|| ::rsweb_lib::validate::anchored(#pattern).expect("checked by the derive"));
+                ::rsweb_lib::validate::pattern(value, regex)
+            }),
+            | Rule::Email => quote!(::rsweb_lib::validate::email(value)),
+            | Rule::Url => quote!(::rsweb_lib::validate::url(value)),
+            | Rule::OneOf(allowed) => quote!(::rsweb_lib::validate::one_of(value, &[#(#allowed),*])),
+            | Rule::Custom(path) => quote!(#path(value)),
+        }
+    }
+}
+
+/// The `min = ...` and `max = ...` items of `length(...)` and `range(...)`.
+fn bounds (nested: &Punctuated<NestedMeta, Token![,]>)
+           -> Result<Vec<(String, Lit)>>
+{
+    nested.iter().map(|meta| match meta {
+        | NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit, .. }))
+        if path.is_ident("min") || path.is_ident("max")
+        => Ok((path.get_ident().expect("Unreachable").to_string(), lit.clone())),
+        | other => Err(Error::new(other.span(), "Expected `min = ...` or `max = ...`")),
+    }).collect()
+}